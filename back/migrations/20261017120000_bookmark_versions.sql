@@ -0,0 +1,18 @@
+alter table users add column sync_version bigint not null default 0;
+alter table bookmarks add column version bigint not null default 0;
+
+-- number existing rows per user like the server numbers new writes, so each
+-- has its own version and clients paging from 0 still receive all of them
+update bookmarks b set version = numbered.version
+from (
+    select id, row_number() over (partition by user_id order by id) as version
+    from bookmarks
+) numbered
+where b.id = numbered.id;
+
+update users u set sync_version = coalesce(
+    (select max(version) from bookmarks b where b.user_id = u.id),
+    0
+);
+
+create index bookmarks_user_id_version_idx on bookmarks (user_id, version);
@@ -0,0 +1,13 @@
+create table changes (
+    user_id varchar(30) not null references users(id),
+    seq bigint not null,
+    bookmark_id varchar(30) not null references bookmarks(id),
+    op varchar(10) not null,
+    created_at timestamptz not null default now(),
+
+    primary key (user_id, seq)
+);
+
+insert into changes (user_id, seq, bookmark_id, op)
+select user_id, version, id, case when deleted_at is null then 'upsert' else 'delete' end
+from bookmarks;
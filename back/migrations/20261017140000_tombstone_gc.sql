@@ -0,0 +1,6 @@
+-- highest change seq that may have been purged, clients whose cursor is older
+-- than this might have missed deletes
+alter table users add column purged_seq bigint not null default 0;
+
+create index changes_bookmark_id_idx on changes (bookmark_id);
+create index changes_created_at_idx on changes (created_at);
@@ -0,0 +1,13 @@
+create table idempotency_keys (
+    user_id varchar(30) not null references users(id),
+    key varchar(255) not null,
+    -- sha-256 of the request, reusing a key for another request is an error
+    request_hash text not null,
+    -- null while the first request with the key is still running
+    response jsonb,
+    created_at timestamptz not null default now(),
+
+    primary key (user_id, key)
+);
+
+create index idempotency_keys_created_at_idx on idempotency_keys (created_at);
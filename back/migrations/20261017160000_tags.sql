@@ -0,0 +1,27 @@
+create table tags (
+    id varchar(30) primary key not null,
+    user_id varchar(30) not null references users(id),
+    name varchar(100) not null,
+
+    unique (user_id, name)
+);
+
+-- assignments are part of the bookmark's state, changing them gives the
+-- bookmark a new version
+create table bookmark_tags (
+    bookmark_id varchar(30) not null references bookmarks(id) on delete cascade,
+    tag_id varchar(30) not null references tags(id) on delete cascade,
+
+    primary key (bookmark_id, tag_id)
+);
+
+create index bookmark_tags_tag_id_idx on bookmark_tags (tag_id);
+
+-- tag names of a bookmark sorted bytewise, the order clients sort them in
+create function bookmark_tag_names(bookmark_id varchar) returns text[]
+language sql stable as $$
+    select coalesce(array_agg(t.name order by t.name collate "C"), '{}')
+    from bookmark_tags bt
+    join tags t on t.id = bt.tag_id
+    where bt.bookmark_id = $1
+$$;
@@ -0,0 +1,26 @@
+create table collections (
+    id varchar(30) primary key not null,
+    user_id varchar(30) not null references users(id),
+    -- not a foreign key, syncs may send children before their parents and the
+    -- server keeps the tree consistent itself
+    parent_id varchar(30),
+    name varchar(100) not null,
+    position bigint not null default 0,
+    updated_at timestamptz not null,
+    deleted_at timestamptz,
+    version bigint not null
+);
+
+create index collections_user_id_idx on collections (user_id);
+
+alter table bookmarks add column collection_id varchar(30);
+alter table bookmarks add column position bigint not null default 0;
+
+create index bookmarks_collection_id_idx on bookmarks (collection_id);
+
+-- every change is to either a bookmark or a collection
+alter table changes alter column bookmark_id drop not null;
+alter table changes add column collection_id varchar(30) references collections(id);
+alter table changes add constraint changes_one_item check (num_nonnulls(bookmark_id, collection_id) = 1);
+
+create index changes_collection_id_idx on changes (collection_id);
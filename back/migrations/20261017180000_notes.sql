@@ -0,0 +1 @@
+alter table bookmarks add column notes text not null default '';
@@ -0,0 +1,4 @@
+-- real titles and urls with tracking params don't fit the old limits, the
+-- server validates lengths before writing instead
+alter table bookmarks alter column title type text;
+alter table bookmarks alter column url type text;
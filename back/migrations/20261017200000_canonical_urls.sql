@@ -0,0 +1,15 @@
+-- filled by the server on every write, existing rows are backfilled by the
+-- garbage collector as the rules live in the server's config
+alter table bookmarks add column canonical_url text;
+
+-- when the server first saw the bookmark, duplicates merge into the oldest
+alter table bookmarks add column created_at timestamptz not null default now();
+
+create index bookmarks_user_id_canonical_url_idx on bookmarks (user_id, canonical_url);
+
+-- existing rows get the time in their ulid instead of the migration's
+update bookmarks set created_at = to_timestamp((
+    select sum((strpos('0123456789ABCDEFGHJKMNPQRSTVWXYZ', substr(upper(id), i, 1)) - 1)::numeric * power(32::numeric, 10 - i))
+    from generate_series(1, 10) i
+) / 1000.0)
+where id ~* '^[0-7][0-9A-HJKMNP-TV-Z]{25}$';
@@ -0,0 +1,9 @@
+-- urls are split into words and indexed without stemming, hosts and paths
+-- aren't english
+alter table bookmarks add column search_vector tsvector generated always as (
+    setweight(to_tsvector('english', title), 'A') ||
+    setweight(to_tsvector('english', notes), 'B') ||
+    setweight(to_tsvector('simple', regexp_replace(url, '[^[:alnum:]]+', ' ', 'g')), 'C')
+) stored;
+
+create index bookmarks_search_vector_idx on bookmarks using gin (search_vector);
@@ -0,0 +1,12 @@
+create extension if not exists pg_trgm;
+
+alter table bookmarks add column url_host text generated always as (
+    regexp_replace(
+        lower(substring(url from '^[[:alpha:]][[:alnum:]+.-]*://(?:[^/@]*@)?([^/:?#]+)')),
+        '^www\.',
+        ''
+    )
+) stored;
+
+create index bookmarks_title_trgm_idx on bookmarks using gin (lower(title) gin_trgm_ops);
+create index bookmarks_url_host_trgm_idx on bookmarks using gin (url_host gin_trgm_ops);
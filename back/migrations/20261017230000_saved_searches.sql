@@ -0,0 +1,20 @@
+create table saved_searches (
+    id varchar(30) primary key not null,
+    user_id varchar(30) not null references users(id),
+    name varchar(100) not null,
+    -- in the search query language, parsed whenever the search runs
+    query text not null,
+    position bigint not null default 0,
+    updated_at timestamptz not null,
+    deleted_at timestamptz,
+    version bigint not null
+);
+
+create index saved_searches_user_id_idx on saved_searches (user_id);
+
+-- every change is to a bookmark, a collection or a saved search
+alter table changes add column saved_search_id varchar(30) references saved_searches(id);
+alter table changes drop constraint changes_one_item;
+alter table changes add constraint changes_one_item check (num_nonnulls(bookmark_id, collection_id, saved_search_id) = 1);
+
+create index changes_saved_search_id_idx on changes (saved_search_id);
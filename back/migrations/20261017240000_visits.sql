@@ -0,0 +1,25 @@
+-- visits are kept as a count and the latest few timestamps, enough for
+-- frecency without a row per visit
+alter table bookmarks add column visit_count bigint not null default 0;
+alter table bookmarks add column recent_visits timestamptz[] not null default '{}';
+
+-- the version of the last write that wasn't a visit, edits based on any
+-- version since then only raced with visits and still apply
+alter table bookmarks add column edit_version bigint not null default 0;
+update bookmarks set edit_version = version;
+
+-- firefox's frecency without visit type bonuses: the visit count times the
+-- average weight of the sampled recent visits, recent visits weigh more
+create function bookmark_frecency(visit_count bigint, recent_visits timestamptz[]) returns real
+language sql stable as $$
+    select coalesce(visit_count * ceil(avg(
+        case
+            when now() - v <= interval '4 days' then 100
+            when now() - v <= interval '14 days' then 70
+            when now() - v <= interval '31 days' then 50
+            when now() - v <= interval '90 days' then 30
+            else 10
+        end
+    )), 0)::real
+    from unnest(recent_visits) v
+$$;
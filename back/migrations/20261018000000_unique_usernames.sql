@@ -0,0 +1,6 @@
+-- usernames were only checked before writing, so concurrent requests could
+-- take the same one. later duplicates get their id appended
+update users u set username = left(u.username, 69) || '-' || u.id
+where exists (select 1 from users o where o.username = u.username and o.id < u.id);
+
+create unique index users_username_idx on users (username);
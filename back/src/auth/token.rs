@@ -11,7 +11,7 @@ pub fn create_token(secret: &str, user_id: &str, session_id: &str) -> String {
 
     let signature = create_signature(secret, &data);
 
-    return format!("{data}{TOKEN_SEPARATOR}{signature}");
+    format!("{data}{TOKEN_SEPARATOR}{signature}")
 }
 
 pub fn verify_token(secret: &str, token: &str) -> Result<(String, String), anyhow::Error> {
@@ -37,7 +37,7 @@ pub fn verify_token(secret: &str, token: &str) -> Result<(String, String), anyho
         return Err(anyhow::anyhow!("invalid signature"));
     }
 
-    return Ok((user_id.to_owned(), session_id.to_owned()));
+    Ok((user_id.to_owned(), session_id.to_owned()))
 }
 
 pub fn timing_safe_equals(a: &[u8], b: &[u8]) -> bool {
@@ -61,5 +61,5 @@ fn create_signature(secret: &str, data_to_sign: &str) -> String {
     let result = mac.finalize();
     let result = result.into_bytes();
 
-    return hex::encode(result);
+    hex::encode(result)
 }
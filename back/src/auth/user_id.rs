@@ -47,7 +47,7 @@ where
             .context("error getting context")?
             .ok_or(ApiError::Unauthorized("no session".to_owned()))?;
 
-        return Ok(UserId(user_id.to_owned()));
+        Ok(UserId(user_id.to_owned()))
     }
 }
 
@@ -93,9 +93,9 @@ where
             .context("error getting context")?
             .ok_or(ApiError::Unauthorized("no session".to_owned()))?;
 
-        return Ok(Auth(AuthData {
+        Ok(Auth(AuthData {
             user_id: user_id.to_owned(),
             session_id: session_id.to_owned(),
-        }));
+        }))
     }
 }
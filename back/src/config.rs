@@ -8,6 +8,34 @@ pub struct Config {
     pub front_url: String,
     pub secret: String,
     pub is_prod: bool,
+    #[serde(default = "default_tombstone_retention_days")]
+    pub tombstone_retention_days: i64,
+    #[serde(default = "default_idempotency_window_hours")]
+    pub idempotency_window_hours: i64,
+    /// Comma separated, allowed in bookmark urls on top of http and https.
+    #[serde(default)]
+    pub extra_url_schemes: Vec<String>,
+    /// Comma separated query params that are left out of canonical urls, a
+    /// trailing `*` matches any suffix.
+    #[serde(default = "default_tracking_params")]
+    pub tracking_params: Vec<String>,
+}
+
+fn default_tombstone_retention_days() -> i64 {
+    90
+}
+
+fn default_idempotency_window_hours() -> i64 {
+    24
+}
+
+fn default_tracking_params() -> Vec<String> {
+    [
+        "utm_*", "fbclid", "gclid", "dclid", "msclkid", "yclid", "mc_cid", "mc_eid", "igshid",
+        "_hsenc", "_hsmi", "ref_src",
+    ]
+    .map(str::to_owned)
+    .to_vec()
 }
 
 impl Config {
@@ -16,7 +44,7 @@ impl Config {
 
         let config = envy::from_env::<Self>().context("invalid environment variables")?;
 
-        return Ok(config);
+        Ok(config)
     }
 }
 
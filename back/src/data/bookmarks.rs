@@ -1,6 +1,15 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Context};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use sqlx::{query, query_as, PgPool, Postgres, QueryBuilder};
+use sqlx::{query, query_as, query_scalar, PgConnection, PgPool};
+
+use super::{announce_new_matches, get_matches, lock_user, notify_changes, ChangeNotification};
+use crate::{id::new_id, url_policy};
+
+// firefox samples the same number of visits for frecency
+const RECENT_VISITS: i64 = 10;
 
 #[derive(Clone)]
 pub struct Bookmarks {
@@ -8,93 +17,830 @@ pub struct Bookmarks {
 }
 
 impl Bookmarks {
-    pub async fn upsert(&self, user_id: &str, bookmark: &Bookmark) -> anyhow::Result<()> {
+    /// Every written row gets a new version from the user's sync counter and a
+    /// matching entry in the change log, which is announced to listeners. Tag
+    /// assignments of written rows are replaced by the given ones. An existing
+    /// row is only overwritten when it's owned by `user_id` and nothing but
+    /// visits changed it since the version the client based the change on,
+    /// the written rows are returned. Rows that start matching a saved search
+    /// are announced too.
+    pub async fn bulk_upsert(
+        &self,
+        user_id: &str,
+        origin: Option<&str>,
+        bookmarks: &[Bookmark],
+    ) -> anyhow::Result<Vec<Bookmark>> {
+        if bookmarks.is_empty() {
+            return Ok(vec![]);
+        }
+
+        // every write path ends up here, so nothing gets past the url policy
+        // even when a caller forgets to validate
+        for bookmark in bookmarks.iter().filter(|b| b.deleted_at.is_none()) {
+            url_policy::normalize(&bookmark.url)
+                .map_err(|err| anyhow!("refusing url of bookmark {}: {err}", bookmark.id))?;
+        }
+
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .context("error beginning transaction")?;
+
+        // also locks the user row, so concurrent syncs of the same user commit
+        // their versions in order and the version check below can't race
+        let last_version = query_scalar!(
+            r#"
+            update users set sync_version = sync_version + $2
+            where id = $1
+            returning sync_version
+            "#,
+            user_id,
+            bookmarks.len() as i64,
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .context("error bumping sync version")?;
+
+        let first_version = last_version - bookmarks.len() as i64 + 1;
+
+        let mut ids = Vec::with_capacity(bookmarks.len());
+        let mut titles = Vec::with_capacity(bookmarks.len());
+        let mut urls = Vec::with_capacity(bookmarks.len());
+        let mut deleted_ats = Vec::with_capacity(bookmarks.len());
+        let mut updated_ats = Vec::with_capacity(bookmarks.len());
+        let mut versions = Vec::with_capacity(bookmarks.len());
+        let mut base_versions = Vec::with_capacity(bookmarks.len());
+        let mut collection_ids = Vec::with_capacity(bookmarks.len());
+        let mut collection_ids_set = Vec::with_capacity(bookmarks.len());
+        let mut positions = Vec::with_capacity(bookmarks.len());
+        let mut notes = Vec::with_capacity(bookmarks.len());
+        let mut canonical_urls = Vec::with_capacity(bookmarks.len());
+
+        for (i, bookmark) in bookmarks.iter().enumerate() {
+            ids.push(bookmark.id.to_owned());
+            titles.push(bookmark.title.to_owned());
+            urls.push(bookmark.url.to_owned());
+            deleted_ats.push(bookmark.deleted_at);
+            updated_ats.push(bookmark.updated_at);
+            versions.push(first_version + i as i64);
+            base_versions.push(bookmark.version);
+            collection_ids.push(bookmark.collection_id.to_owned().flatten());
+            collection_ids_set.push(bookmark.collection_id.is_some());
+            positions.push(bookmark.position);
+            notes.push(bookmark.notes.to_owned());
+            canonical_urls.push(url_policy::canonicalize(&bookmark.url));
+        }
+
+        let matched_before = get_matches(&mut tx, user_id, &ids)
+            .await
+            .context("error matching saved searches")?;
+
+        // binding one array per column keeps the parameter count fixed, so
+        // batches of any size fit in a single statement
+        let written = query_as!(
+            BookmarkRow,
+            r#"
+            WITH incoming AS (
+                SELECT *
+                FROM UNNEST(
+                    $2::text[],
+                    $3::text[],
+                    $4::text[],
+                    $5::timestamptz[],
+                    $6::timestamptz[],
+                    $7::int8[],
+                    $8::int8[],
+                    $9::text[],
+                    $10::bool[],
+                    $11::int8[],
+                    $12::text[],
+                    $13::text[]
+                ) AS t(id, title, url, deleted_at, updated_at, version, base_version, collection_id, collection_id_set, position, notes, canonical_url)
+            ), merged AS (
+                -- fields the client left out keep their stored values
+                SELECT
+                    incoming.id, incoming.title, incoming.url, incoming.deleted_at, incoming.updated_at,
+                    incoming.version, incoming.canonical_url,
+                    CASE WHEN incoming.collection_id_set THEN incoming.collection_id ELSE existing.collection_id END AS collection_id,
+                    coalesce(incoming.position, existing.position, 0) AS position,
+                    coalesce(incoming.notes, existing.notes, '') AS notes
+                FROM incoming
+                LEFT JOIN bookmarks existing ON existing.id = incoming.id
+                WHERE (
+                    existing.id IS NULL
+                    OR (
+                        existing.user_id = $1
+                        AND incoming.base_version BETWEEN existing.edit_version AND existing.version
+                    )
+                )
+            ), allowed AS (
+                SELECT *
+                FROM merged
+                -- live bookmarks can only be in live collections
+                WHERE (
+                    merged.collection_id IS NULL
+                    OR merged.deleted_at IS NOT NULL
+                    OR EXISTS (
+                        SELECT 1 FROM collections c
+                        WHERE c.id = merged.collection_id
+                        AND c.user_id = $1
+                        AND c.deleted_at IS NULL
+                    )
+                )
+            ), written AS (
+                INSERT INTO bookmarks (id, title, url, deleted_at, updated_at, version, edit_version, collection_id, position, notes, canonical_url, user_id)
+                SELECT id, title, url, deleted_at, updated_at, version, version, collection_id, position, notes, canonical_url, $1
+                FROM allowed
+                ON CONFLICT (id) DO UPDATE SET
+                    title = EXCLUDED.title,
+                    url = EXCLUDED.url,
+                    deleted_at = EXCLUDED.deleted_at,
+                    updated_at = EXCLUDED.updated_at,
+                    version = EXCLUDED.version,
+                    edit_version = EXCLUDED.edit_version,
+                    collection_id = EXCLUDED.collection_id,
+                    position = EXCLUDED.position,
+                    notes = EXCLUDED.notes,
+                    canonical_url = EXCLUDED.canonical_url
+                WHERE bookmarks.user_id = EXCLUDED.user_id
+                RETURNING id, title, url, deleted_at, updated_at, version, collection_id, position, notes,
+                    visit_count, recent_visits[1] AS last_visited_at, user_id
+            ), logged AS (
+                INSERT INTO changes (user_id, seq, bookmark_id, op)
+                SELECT user_id, version, id, CASE WHEN deleted_at IS NULL THEN 'upsert' ELSE 'delete' END
+                FROM written
+            )
+            SELECT
+                id as "id!",
+                title as "title!",
+                url as "url!",
+                deleted_at,
+                updated_at as "updated_at!",
+                version as "version!",
+                bookmark_tag_names(id) as "tags!",
+                collection_id,
+                position as "position!",
+                notes as "notes!",
+                visit_count as "visit_count!",
+                last_visited_at
+            FROM written
+            "#,
+            user_id,
+            &ids,
+            &titles,
+            &urls,
+            &deleted_ats as &[Option<DateTime<Utc>>],
+            &updated_ats,
+            &versions,
+            &base_versions,
+            &collection_ids as &[Option<String>],
+            &collection_ids_set,
+            &positions as &[Option<i64>],
+            &notes as &[Option<String>],
+            &canonical_urls,
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .context("error upserting bookmarks")?;
+
+        let tags = bookmarks
+            .iter()
+            .map(|b| (b.id.as_str(), &b.tags))
+            .collect::<HashMap<_, _>>();
+
+        // the rows come with the tags from before the write, which are still
+        // right for bookmarks that were written without tags
+        let written = written
+            .into_iter()
+            .map(Bookmark::from)
+            .map(|b| Bookmark {
+                tags: tags[b.id.as_str()].to_owned().or(b.tags),
+                ..b
+            })
+            .collect::<Vec<_>>();
+
+        set_tags(&mut tx, user_id, &written)
+            .await
+            .context("error setting tags")?;
+
+        let written_ids = written.iter().map(|b| b.id.to_owned()).collect::<Vec<_>>();
+
+        announce_new_matches(&mut tx, user_id, &matched_before, &written_ids)
+            .await
+            .context("error announcing saved search matches")?;
+
+        if !written.is_empty() {
+            notify_changes(
+                &mut tx,
+                &ChangeNotification {
+                    user_id: user_id.to_owned(),
+                    after_seq: first_version - 1,
+                    last_seq: last_version,
+                    origin: origin.map(str::to_owned),
+                },
+            )
+            .await
+            .context("error notifying changes")?;
+        }
+
+        tx.commit().await.context("error committing transaction")?;
+
+        Ok(written)
+    }
+
+    /// Adds the visits to the user's bookmarks that aren't deleted, keeping
+    /// their count and the latest timestamps. Visited bookmarks get new
+    /// versions like any other write but keep their `updated_at` and
+    /// `edit_version`, so edits based on the version before the visit still
+    /// apply. The visited bookmarks are returned.
+    pub async fn record_visits(
+        &self,
+        user_id: &str,
+        origin: Option<&str>,
+        visits: &[Visit],
+    ) -> anyhow::Result<Vec<Bookmark>> {
+        if visits.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .context("error beginning transaction")?;
+
+        lock_user(&mut tx, user_id).await?;
+
+        let ids = visits
+            .iter()
+            .map(|v| v.bookmark_id.to_owned())
+            .collect::<Vec<_>>();
+        let visited_ats = visits.iter().map(|v| v.visited_at).collect::<Vec<_>>();
+
+        let visited = query_scalar!(
+            r#"
+            SELECT id FROM bookmarks
+            WHERE user_id = $1 AND id = ANY($2) AND deleted_at IS NULL
+            ORDER BY id
+            "#,
+            user_id,
+            &ids,
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .context("error getting visited bookmarks")?;
+
+        if visited.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let last_version = query_scalar!(
+            r#"
+            UPDATE users SET sync_version = sync_version + $2
+            WHERE id = $1
+            RETURNING sync_version
+            "#,
+            user_id,
+            visited.len() as i64,
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .context("error bumping sync version")?;
+
+        let first_version = last_version - visited.len() as i64 + 1;
+        let versions = (first_version..=last_version).collect::<Vec<_>>();
+
         query!(
             r#"
-            insert into bookmarks (user_id, id, title, url, deleted_at, updated_at)
-            values ($1, $2, $3, $4, $5, $6)
-            on conflict(id)
-            do update set
-                title = $3,
-                url = $4,
-                deleted_at = $5,
-                updated_at = $6
+            WITH counted AS (
+                SELECT id, COUNT(*) AS count, array_agg(visited_at) AS visited_ats
+                FROM UNNEST($2::text[], $3::timestamptz[]) AS t(id, visited_at)
+                GROUP BY id
+            ), updated AS (
+                UPDATE bookmarks b SET
+                    visit_count = b.visit_count + c.count,
+                    recent_visits = ARRAY(
+                        SELECT v FROM unnest(b.recent_visits || c.visited_ats) v
+                        ORDER BY v DESC
+                        LIMIT $6
+                    ),
+                    version = t.version
+                FROM counted c
+                JOIN UNNEST($4::text[], $5::int8[]) AS t(id, version) ON t.id = c.id
+                WHERE b.id = c.id AND b.user_id = $1
+                RETURNING b.id, b.version
+            )
+            INSERT INTO changes (user_id, seq, bookmark_id, op)
+            SELECT $1, version, id, 'upsert'
+            FROM updated
+            "#,
+            user_id,
+            &ids,
+            &visited_ats,
+            &visited,
+            &versions,
+            RECENT_VISITS,
+        )
+        .execute(&mut *tx)
+        .await
+        .context("error recording visits")?;
+
+        notify_changes(
+            &mut tx,
+            &ChangeNotification {
+                user_id: user_id.to_owned(),
+                after_seq: first_version - 1,
+                last_seq: last_version,
+                origin: origin.map(str::to_owned),
+            },
+        )
+        .await
+        .context("error notifying changes")?;
+
+        tx.commit().await.context("error committing transaction")?;
+
+        self.get_many(user_id, &visited).await
+    }
+
+    /// The user's bookmarks that aren't deleted.
+    pub async fn get_all(&self, user_id: &str) -> anyhow::Result<Vec<Bookmark>> {
+        let bookmarks = query_as!(
+            BookmarkRow,
+            r#"
+            SELECT id, title, url, deleted_at, updated_at, version, bookmark_tag_names(id) as "tags!",
+                collection_id, position, notes, visit_count, recent_visits[1] as last_visited_at
+            FROM bookmarks
+            WHERE user_id = $1
+            AND deleted_at IS NULL
+            ORDER BY position, id
             "#,
             user_id,
-            &bookmark.id,
-            &bookmark.title,
-            &bookmark.url,
-            bookmark.deleted_at,
-            bookmark.updated_at,
         )
-        .execute(&self.pool)
+        .fetch_all(&self.pool)
         .await?;
 
-        Ok(())
+        Ok(bookmarks.into_iter().map(Bookmark::from).collect())
     }
 
-    pub async fn bulk_upsert(&self, user_id: &str, bookmarks: &[Bookmark]) -> anyhow::Result<()> {
-        if bookmarks.is_empty() {
-            return Ok(());
-        }
+    /// Groups of the user's bookmarks that aren't deleted and share a canonical
+    /// url, oldest first within each group.
+    pub async fn get_duplicates(&self, user_id: &str) -> anyhow::Result<Vec<DuplicateGroup>> {
+        let groups = query!(
+            r#"
+            SELECT canonical_url as "canonical_url!", array_agg(id ORDER BY created_at, id) as "ids!"
+            FROM bookmarks
+            WHERE user_id = $1
+            AND deleted_at IS NULL
+            AND canonical_url IS NOT NULL
+            GROUP BY canonical_url
+            HAVING COUNT(*) > 1
+            ORDER BY canonical_url
+            "#,
+            user_id,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let ids = groups
+            .iter()
+            .flat_map(|group| group.ids.iter().cloned())
+            .collect::<Vec<_>>();
+
+        let mut bookmarks = self
+            .get_many(user_id, &ids)
+            .await?
+            .into_iter()
+            .map(|b| (b.id.to_owned(), b))
+            .collect::<HashMap<_, _>>();
+
+        let groups = groups
+            .into_iter()
+            .map(|group| DuplicateGroup {
+                bookmarks: group
+                    .ids
+                    .iter()
+                    .filter_map(|id| bookmarks.remove(id))
+                    .collect(),
+                canonical_url: group.canonical_url,
+            })
+            .collect();
 
-        let mut query_builder: QueryBuilder<Postgres> = QueryBuilder::new(
-            "INSERT INTO bookmarks (id, title, url, deleted_at, updated_at, user_id) ",
-        );
-
-        query_builder.push_values(bookmarks, |mut b, bookmark| {
-            b.push_bind(&bookmark.id)
-                .push_bind(&bookmark.title)
-                .push_bind(&bookmark.url)
-                .push_bind(bookmark.deleted_at)
-                .push_bind(bookmark.updated_at)
-                .push_bind(user_id);
-        });
-
-        query_builder.push(
-            " ON CONFLICT (id) DO UPDATE SET 
-                title = EXCLUDED.title,
-                url = EXCLUDED.url,
-                deleted_at = EXCLUDED.deleted_at,
-                updated_at = EXCLUDED.updated_at",
-        );
-
-        let query = query_builder.build();
-        query.execute(&self.pool).await?;
-
-        Ok(())
+        Ok(groups)
     }
 
-    pub async fn get_all(
+    /// Merges the user's bookmarks with the canonical url into the oldest one,
+    /// which gets the tags and notes of all of them, and deletes the others.
+    /// Returns the kept bookmark, `None` when there was nothing to merge.
+    pub async fn merge_duplicates(
         &self,
         user_id: &str,
-        last_synced_at: &DateTime<Utc>,
-        cursor: Option<&str>,
-        limit: i64,
-    ) -> anyhow::Result<Vec<Bookmark>> {
+        origin: Option<&str>,
+        canonical_url: &str,
+    ) -> anyhow::Result<Option<Bookmark>> {
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .context("error beginning transaction")?;
+
+        lock_user(&mut tx, user_id).await?;
+
+        let group = query!(
+            r#"
+            SELECT id, notes
+            FROM bookmarks
+            WHERE user_id = $1
+            AND canonical_url = $2
+            AND deleted_at IS NULL
+            ORDER BY created_at, id
+            "#,
+            user_id,
+            canonical_url,
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .context("error getting duplicates")?;
+
+        if group.len() < 2 {
+            return Ok(None);
+        }
+
+        let ids = group.iter().map(|b| b.id.to_owned()).collect::<Vec<_>>();
+
+        let matched_before = get_matches(&mut tx, user_id, &ids)
+            .await
+            .context("error matching saved searches")?;
+
+        let kept = group[0].id.to_owned();
+        let others = group[1..]
+            .iter()
+            .map(|b| b.id.to_owned())
+            .collect::<Vec<_>>();
+
+        let mut notes = Vec::<&str>::new();
+
+        for bookmark in &group {
+            let note = bookmark.notes.trim();
+
+            if !note.is_empty() && !notes.contains(&note) {
+                notes.push(note);
+            }
+        }
+
+        query!(
+            r#"
+            INSERT INTO bookmark_tags (bookmark_id, tag_id)
+            SELECT $1, tag_id FROM bookmark_tags WHERE bookmark_id = ANY($2)
+            ON CONFLICT DO NOTHING
+            "#,
+            kept,
+            &others,
+        )
+        .execute(&mut *tx)
+        .await
+        .context("error merging tags")?;
+
+        query!(
+            r#"UPDATE bookmarks SET notes = $2 WHERE id = $1"#,
+            kept,
+            notes.join("\n\n"),
+        )
+        .execute(&mut *tx)
+        .await
+        .context("error merging notes")?;
+
+        query!(
+            r#"UPDATE bookmarks SET deleted_at = now() WHERE id = ANY($1)"#,
+            &others,
+        )
+        .execute(&mut *tx)
+        .await
+        .context("error deleting duplicates")?;
+
+        bump_versions(&mut tx, user_id, origin, &ids)
+            .await
+            .context("error bumping bookmark versions")?;
+
+        announce_new_matches(&mut tx, user_id, &matched_before, &ids)
+            .await
+            .context("error announcing saved search matches")?;
+
+        tx.commit().await.context("error committing transaction")?;
+
+        let kept = self.get_many(user_id, &[kept]).await?.pop();
+
+        Ok(kept)
+    }
+
+    /// Fills in canonical urls of bookmarks written before they existed.
+    /// Returns the number of updated bookmarks.
+    pub async fn backfill_canonical_urls(&self) -> anyhow::Result<u64> {
+        let mut updated = 0;
+
+        loop {
+            let rows =
+                query!(r#"SELECT id, url FROM bookmarks WHERE canonical_url IS NULL LIMIT 1000"#)
+                    .fetch_all(&self.pool)
+                    .await?;
+
+            if rows.is_empty() {
+                return Ok(updated);
+            }
+
+            let ids = rows.iter().map(|r| r.id.to_owned()).collect::<Vec<_>>();
+            let canonical_urls = rows
+                .iter()
+                .map(|r| url_policy::canonicalize(&r.url))
+                .collect::<Vec<_>>();
+
+            let result = query!(
+                r#"
+                UPDATE bookmarks b
+                SET canonical_url = t.canonical_url
+                FROM UNNEST($1::text[], $2::text[]) AS t(id, canonical_url)
+                WHERE b.id = t.id
+                "#,
+                &ids,
+                &canonical_urls,
+            )
+            .execute(&self.pool)
+            .await?;
+
+            updated += result.rows_affected();
+        }
+    }
+
+    /// Deletes live bookmarks whose url has a scheme the url policy doesn't
+    /// allow, which were saved before it existed or before their scheme was
+    /// taken out of `EXTRA_URL_SCHEMES`. Returns the number of deleted
+    /// bookmarks.
+    pub async fn tombstone_unsafe_urls(&self) -> anyhow::Result<u64> {
+        // narrows it down to the few urls that can have another scheme
+        let rows = query!(
+            r#"
+            SELECT user_id, id, url
+            FROM bookmarks
+            WHERE deleted_at IS NULL
+            AND url NOT ILIKE 'https://%'
+            AND url NOT ILIKE 'http://%'
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut unsafe_ids = HashMap::<_, Vec<_>>::new();
+
+        for row in rows.into_iter().filter(|r| url_policy::is_unsafe(&r.url)) {
+            unsafe_ids.entry(row.user_id).or_default().push(row.id);
+        }
+
+        let mut deleted = 0;
+
+        for (user_id, ids) in unsafe_ids {
+            let mut tx = self
+                .pool
+                .begin()
+                .await
+                .context("error beginning transaction")?;
+
+            lock_user(&mut tx, &user_id).await?;
+
+            let ids = query_scalar!(
+                r#"
+                UPDATE bookmarks SET deleted_at = now()
+                WHERE user_id = $1 AND id = ANY($2)
+                AND deleted_at IS NULL
+                RETURNING id
+                "#,
+                user_id,
+                &ids,
+            )
+            .fetch_all(&mut *tx)
+            .await
+            .context("error deleting bookmarks")?;
+
+            bump_versions(&mut tx, &user_id, None, &ids).await?;
+
+            tx.commit().await.context("error committing transaction")?;
+
+            deleted += ids.len() as u64;
+        }
+
+        Ok(deleted)
+    }
+
+    pub async fn get_many(&self, user_id: &str, ids: &[String]) -> anyhow::Result<Vec<Bookmark>> {
         let bookmarks = query_as!(
-            Bookmark,
+            BookmarkRow,
             r#"
-            SELECT id, title, url, deleted_at, updated_at
+            SELECT id, title, url, deleted_at, updated_at, version, bookmark_tag_names(id) as "tags!",
+                collection_id, position, notes, visit_count, recent_visits[1] as last_visited_at
             FROM bookmarks
             WHERE user_id = $1
-            AND updated_at > $2
-            AND ($3::text IS NULL OR id > $3)
-            ORDER BY id
-            LIMIT $4
+            AND id = ANY($2)
             "#,
             user_id,
-            last_synced_at,
-            cursor,
-            limit,
+            ids,
         )
         .fetch_all(&self.pool)
         .await?;
 
-        Ok(bookmarks)
+        Ok(bookmarks.into_iter().map(Bookmark::from).collect())
+    }
+
+    /// Deletes bookmarks whose deletion reached the server before `before`
+    /// along with their change log entries, and moves each affected user's
+    /// purge watermark past the purged changes. Returns the number of purged
+    /// bookmarks.
+    pub async fn purge_tombstones(&self, before: &DateTime<Utc>) -> anyhow::Result<i64> {
+        let purged = query_scalar!(
+            r#"
+            WITH purged AS (
+                DELETE FROM bookmarks b
+                USING changes c
+                WHERE c.bookmark_id = b.id
+                AND c.seq = b.version
+                AND c.op = 'delete'
+                AND c.created_at < $1
+                RETURNING b.id, b.user_id, b.version
+            ), purged_changes AS (
+                DELETE FROM changes
+                WHERE bookmark_id IN (SELECT id FROM purged)
+            ), watermarks AS (
+                UPDATE users
+                SET purged_seq = GREATEST(users.purged_seq, p.max_version)
+                FROM (
+                    SELECT user_id, MAX(version) AS max_version
+                    FROM purged
+                    GROUP BY user_id
+                ) p
+                WHERE users.id = p.user_id
+            )
+            SELECT COUNT(*) as "count!" FROM purged
+            "#,
+            before,
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(purged)
     }
 }
 
+/// Gives the user's bookmarks new versions and change log entries after the
+/// server changed them on its own, e.g. by renaming a tag, and announces the
+/// changes. The user row has to be locked by the transaction already.
+pub(super) async fn bump_versions(
+    conn: &mut PgConnection,
+    user_id: &str,
+    origin: Option<&str>,
+    ids: &[String],
+) -> anyhow::Result<()> {
+    if ids.is_empty() {
+        return Ok(());
+    }
+
+    let last_version = query_scalar!(
+        r#"
+        UPDATE users SET sync_version = sync_version + $2
+        WHERE id = $1
+        RETURNING sync_version
+        "#,
+        user_id,
+        ids.len() as i64,
+    )
+    .fetch_one(&mut *conn)
+    .await
+    .context("error bumping sync version")?;
+
+    let first_version = last_version - ids.len() as i64 + 1;
+    let versions = (first_version..=last_version).collect::<Vec<_>>();
+
+    query!(
+        r#"
+        WITH bumped AS (
+            UPDATE bookmarks b
+            SET version = t.version, edit_version = t.version, updated_at = now()
+            FROM UNNEST($2::text[], $3::int8[]) AS t(id, version)
+            WHERE b.id = t.id AND b.user_id = $1
+            RETURNING b.id, b.version, b.deleted_at
+        )
+        INSERT INTO changes (user_id, seq, bookmark_id, op)
+        SELECT $1, version, id, CASE WHEN deleted_at IS NULL THEN 'upsert' ELSE 'delete' END
+        FROM bumped
+        "#,
+        user_id,
+        ids,
+        &versions,
+    )
+    .execute(&mut *conn)
+    .await
+    .context("error bumping bookmark versions")?;
+
+    notify_changes(
+        conn,
+        &ChangeNotification {
+            user_id: user_id.to_owned(),
+            after_seq: first_version - 1,
+            last_seq: last_version,
+            origin: origin.map(str::to_owned),
+        },
+    )
+    .await
+    .context("error notifying changes")?;
+
+    Ok(())
+}
+
+/// Replaces the tag assignments of the bookmarks that have tags, creating the
+/// user's tags that don't exist yet. Bookmarks without tags keep theirs.
+async fn set_tags(
+    conn: &mut PgConnection,
+    user_id: &str,
+    bookmarks: &[Bookmark],
+) -> anyhow::Result<()> {
+    let mut ids = vec![];
+    let mut bookmark_ids = vec![];
+    let mut names = vec![];
+
+    for bookmark in bookmarks {
+        let Some(tags) = &bookmark.tags else {
+            continue;
+        };
+
+        ids.push(bookmark.id.to_owned());
+
+        for tag in tags {
+            bookmark_ids.push(bookmark.id.to_owned());
+            names.push(tag.to_owned());
+        }
+    }
+
+    if ids.is_empty() {
+        return Ok(());
+    }
+
+    query!(
+        r#"DELETE FROM bookmark_tags WHERE bookmark_id = ANY($1)"#,
+        &ids,
+    )
+    .execute(&mut *conn)
+    .await
+    .context("error deleting tag assignments")?;
+
+    if names.is_empty() {
+        return Ok(());
+    }
+
+    let tag_ids = names.iter().map(|_| new_id()).collect::<Vec<_>>();
+
+    query!(
+        r#"
+        INSERT INTO tags (id, user_id, name)
+        SELECT DISTINCT ON (name) id, $1, name
+        FROM UNNEST($2::text[], $3::text[]) AS t(id, name)
+        ON CONFLICT (user_id, name) DO NOTHING
+        "#,
+        user_id,
+        &tag_ids,
+        &names,
+    )
+    .execute(&mut *conn)
+    .await
+    .context("error inserting tags")?;
+
+    query!(
+        r#"
+        INSERT INTO bookmark_tags (bookmark_id, tag_id)
+        SELECT t.bookmark_id, tags.id
+        FROM UNNEST($2::text[], $3::text[]) AS t(bookmark_id, name)
+        JOIN tags ON tags.user_id = $1 AND tags.name = t.name
+        ON CONFLICT DO NOTHING
+        "#,
+        user_id,
+        &bookmark_ids,
+        &names,
+    )
+    .execute(&mut *conn)
+    .await
+    .context("error inserting tag assignments")?;
+
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Visit {
+    pub bookmark_id: String,
+    pub visited_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DuplicateGroup {
+    pub canonical_url: String,
+    pub bookmarks: Vec<Bookmark>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Bookmark {
     pub id: String,
@@ -102,4 +848,130 @@ pub struct Bookmark {
     pub url: String,
     pub updated_at: DateTime<Utc>,
     pub deleted_at: Option<DateTime<Utc>>,
+    /// Assigned by the server on every write. Clients send back the version
+    /// they last saw, 0 for bookmarks the server doesn't know about yet.
+    #[serde(default)]
+    pub version: i64,
+    /// Names of the user's tags on the bookmark, sorted. Always set by the
+    /// server, clients leave it out to keep the tags as they are.
+    #[serde(default)]
+    pub tags: Option<Vec<String>>,
+    /// `Some(None)` for bookmarks at the root. Always set by the server,
+    /// clients leave it out to keep the bookmark where it is.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_some",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub collection_id: Option<Option<String>>,
+    /// Orders the bookmark among the other items of its collection. Always
+    /// set by the server, clients leave it out to keep the position.
+    #[serde(default)]
+    pub position: Option<i64>,
+    /// Markdown, see [`crate::markdown::render`]. Always set by the server,
+    /// clients leave it out to keep the notes as they are.
+    #[serde(default)]
+    pub notes: Option<String>,
+    /// Counted by the server from recorded visits, syncs leave it alone.
+    #[serde(default)]
+    pub visit_count: i64,
+    #[serde(default)]
+    pub last_visited_at: Option<DateTime<Utc>>,
+}
+
+/// Tells a field that's `null` apart from one that's left out, which
+/// `#[serde(default)]` turns into `None`.
+fn deserialize_some<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: serde::Deserializer<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+/// A bookmark as it's stored, every field is known.
+struct BookmarkRow {
+    id: String,
+    title: String,
+    url: String,
+    updated_at: DateTime<Utc>,
+    deleted_at: Option<DateTime<Utc>>,
+    version: i64,
+    tags: Vec<String>,
+    collection_id: Option<String>,
+    position: i64,
+    notes: String,
+    visit_count: i64,
+    last_visited_at: Option<DateTime<Utc>>,
+}
+
+impl From<BookmarkRow> for Bookmark {
+    fn from(row: BookmarkRow) -> Self {
+        Self {
+            id: row.id,
+            title: row.title,
+            url: row.url,
+            updated_at: row.updated_at,
+            deleted_at: row.deleted_at,
+            version: row.version,
+            tags: Some(row.tags),
+            collection_id: Some(row.collection_id),
+            position: Some(row.position),
+            notes: Some(row.notes),
+            visit_count: row.visit_count,
+            last_visited_at: row.last_visited_at,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        data::{ChangeOp, Data},
+        testing::{bookmark, user},
+    };
+
+    #[sqlx::test]
+    async fn tombstones_unsafe_urls(pool: PgPool) {
+        let data = Data::from_pool(pool);
+        let user_id = user(&data).await;
+
+        let (safe, unsafe_) = (new_id(), new_id());
+
+        data.bookmarks
+            .bulk_upsert(
+                &user_id,
+                None,
+                &[
+                    bookmark(json!({ "id": safe })),
+                    bookmark(json!({ "id": unsafe_ })),
+                ],
+            )
+            .await
+            .unwrap();
+
+        // as saved before the url policy
+        query!(
+            "UPDATE bookmarks SET url = ' JaVaScRiPt:alert(1)' WHERE id = $1",
+            unsafe_
+        )
+        .execute(&data.bookmarks.pool)
+        .await
+        .unwrap();
+
+        assert_eq!(data.bookmarks.tombstone_unsafe_urls().await.unwrap(), 1);
+        assert_eq!(data.bookmarks.tombstone_unsafe_urls().await.unwrap(), 0);
+
+        let live = data.bookmarks.get_all(&user_id).await.unwrap();
+        assert_eq!(live.len(), 1);
+        assert_eq!(live[0].id, safe);
+
+        // clients hear about the deletion
+        let changes = data.changes.get_since(&user_id, 2, 100).await.unwrap();
+        assert_eq!(changes.len(), 1);
+        assert!(matches!(changes[0].op, ChangeOp::Delete));
+    }
 }
@@ -0,0 +1,303 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_as, query_scalar, PgConnection, PgPool};
+
+use super::{notify, Bookmark, Collection, SavedSearch, CHANGES_CHANNEL};
+
+/// Published on [`CHANGES_CHANNEL`] when a transaction writes changes with
+/// seqs in `after_seq + 1..=last_seq` for the user.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChangeNotification {
+    pub user_id: String,
+    pub after_seq: i64,
+    pub last_seq: i64,
+    /// The client that made the changes, see [`crate::events::origin`].
+    pub origin: Option<String>,
+}
+
+pub async fn notify_changes(
+    conn: &mut PgConnection,
+    notification: &ChangeNotification,
+) -> anyhow::Result<()> {
+    let payload = serde_json::to_string(notification)?;
+
+    notify(conn, CHANGES_CHANNEL, &payload).await
+}
+
+#[derive(Clone)]
+pub struct Changes {
+    pub(crate) pool: PgPool,
+}
+
+impl Changes {
+    pub async fn get_purged_seq(&self, user_id: &str) -> anyhow::Result<i64> {
+        let purged_seq = query_scalar!(r#"select purged_seq from users where id = $1"#, user_id)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(purged_seq)
+    }
+
+    /// Drops changes older than `before` that a later change to the same
+    /// bookmark, collection or saved search has superseded. Returns the number
+    /// of dropped changes.
+    pub async fn compact(&self, before: &DateTime<Utc>) -> anyhow::Result<u64> {
+        let bookmarks = query!(
+            r#"
+            delete from changes c
+            using bookmarks b
+            where b.id = c.bookmark_id
+            and c.seq < b.version
+            and c.created_at < $1
+            "#,
+            before,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        let collections = query!(
+            r#"
+            delete from changes c
+            using collections col
+            where col.id = c.collection_id
+            and c.seq < col.version
+            and c.created_at < $1
+            "#,
+            before,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        let saved_searches = query!(
+            r#"
+            delete from changes c
+            using saved_searches s
+            where s.id = c.saved_search_id
+            and c.seq < s.version
+            and c.created_at < $1
+            "#,
+            before,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(
+            bookmarks.rows_affected()
+                + collections.rows_affected()
+                + saved_searches.rows_affected(),
+        )
+    }
+
+    /// Changes that are superseded by a later change to the same item are left
+    /// out, as the later one already carries the item's current state.
+    pub async fn get_since(
+        &self,
+        user_id: &str,
+        after_seq: i64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<Change>> {
+        self.get_range(user_id, after_seq, i64::MAX, limit).await
+    }
+
+    /// Same as [`Self::get_since`] for the changes with seqs in
+    /// `after_seq + 1..=last_seq`.
+    pub async fn get_range(
+        &self,
+        user_id: &str,
+        after_seq: i64,
+        last_seq: i64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<Change>> {
+        let rows = query_as!(
+            ChangeRow,
+            r#"
+            SELECT c.seq, c.op as "op: ChangeOp", b.id, b.title, b.url, b.deleted_at, b.updated_at, b.version,
+                bookmark_tag_names(b.id) as "tags!", b.collection_id, b.position, b.notes,
+                b.visit_count, b.recent_visits[1] as last_visited_at
+            FROM changes c
+            JOIN bookmarks b ON b.id = c.bookmark_id
+            WHERE c.user_id = $1
+            AND c.seq > $2
+            AND c.seq <= $4
+            AND b.version = c.seq
+            ORDER BY c.seq
+            LIMIT $3
+            "#,
+            user_id,
+            after_seq,
+            limit,
+            last_seq,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let collection_rows = query_as!(
+            CollectionChangeRow,
+            r#"
+            SELECT c.seq, c.op as "op: ChangeOp", col.id, col.parent_id, col.name, col.position,
+                col.updated_at, col.deleted_at, col.version
+            FROM changes c
+            JOIN collections col ON col.id = c.collection_id
+            WHERE c.user_id = $1
+            AND c.seq > $2
+            AND c.seq <= $4
+            AND col.version = c.seq
+            ORDER BY c.seq
+            LIMIT $3
+            "#,
+            user_id,
+            after_seq,
+            limit,
+            last_seq,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let saved_search_rows = query_as!(
+            SavedSearchChangeRow,
+            r#"
+            SELECT c.seq, c.op as "op: ChangeOp", s.id, s.name, s.query, s.position,
+                s.updated_at, s.deleted_at, s.version
+            FROM changes c
+            JOIN saved_searches s ON s.id = c.saved_search_id
+            WHERE c.user_id = $1
+            AND c.seq > $2
+            AND c.seq <= $4
+            AND s.version = c.seq
+            ORDER BY c.seq
+            LIMIT $3
+            "#,
+            user_id,
+            after_seq,
+            limit,
+            last_seq,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let saved_search_changes = saved_search_rows.into_iter().map(|row| Change {
+            seq: row.seq,
+            op: row.op,
+            item: ChangeItem::SavedSearch(SavedSearch {
+                id: row.id,
+                name: row.name,
+                query: row.query,
+                position: row.position,
+                updated_at: row.updated_at,
+                deleted_at: row.deleted_at,
+                version: row.version,
+            }),
+        });
+
+        let collection_changes = collection_rows.into_iter().map(|row| Change {
+            seq: row.seq,
+            op: row.op,
+            item: ChangeItem::Collection(Collection {
+                id: row.id,
+                parent_id: row.parent_id,
+                name: row.name,
+                position: row.position,
+                updated_at: row.updated_at,
+                deleted_at: row.deleted_at,
+                version: row.version,
+            }),
+        });
+
+        let mut changes = rows
+            .into_iter()
+            .map(|row| Change {
+                seq: row.seq,
+                op: row.op,
+                item: ChangeItem::Bookmark(Bookmark {
+                    id: row.id,
+                    title: row.title,
+                    url: row.url,
+                    updated_at: row.updated_at,
+                    deleted_at: row.deleted_at,
+                    version: row.version,
+                    tags: Some(row.tags),
+                    collection_id: Some(row.collection_id),
+                    position: Some(row.position),
+                    notes: Some(row.notes),
+                    visit_count: row.visit_count,
+                    last_visited_at: row.last_visited_at,
+                }),
+            })
+            .chain(collection_changes)
+            .chain(saved_search_changes)
+            .collect::<Vec<_>>();
+
+        // each list is the first `limit` of its kind, so the first `limit`
+        // of the merged list are complete
+        changes.sort_by_key(|change| change.seq);
+        changes.truncate(limit as usize);
+
+        Ok(changes)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "varchar", rename_all = "lowercase")]
+pub enum ChangeOp {
+    Upsert,
+    Delete,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Change {
+    pub seq: i64,
+    pub op: ChangeOp,
+    /// Serialized as a `bookmark`, `collection` or `saved_search` field.
+    #[serde(flatten)]
+    pub item: ChangeItem,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeItem {
+    Bookmark(Bookmark),
+    Collection(Collection),
+    SavedSearch(SavedSearch),
+}
+
+struct ChangeRow {
+    seq: i64,
+    op: ChangeOp,
+    id: String,
+    title: String,
+    url: String,
+    updated_at: DateTime<Utc>,
+    deleted_at: Option<DateTime<Utc>>,
+    version: i64,
+    tags: Vec<String>,
+    collection_id: Option<String>,
+    position: i64,
+    notes: String,
+    visit_count: i64,
+    last_visited_at: Option<DateTime<Utc>>,
+}
+
+struct CollectionChangeRow {
+    seq: i64,
+    op: ChangeOp,
+    id: String,
+    parent_id: Option<String>,
+    name: String,
+    position: i64,
+    updated_at: DateTime<Utc>,
+    deleted_at: Option<DateTime<Utc>>,
+    version: i64,
+}
+
+struct SavedSearchChangeRow {
+    seq: i64,
+    op: ChangeOp,
+    id: String,
+    name: String,
+    query: String,
+    position: i64,
+    updated_at: DateTime<Utc>,
+    deleted_at: Option<DateTime<Utc>>,
+    version: i64,
+}
@@ -0,0 +1,624 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_as, query_scalar, PgConnection, PgPool};
+
+use super::{
+    announce_new_matches, bump_versions, get_matches, lock_user, notify_changes, ChangeNotification,
+};
+
+#[derive(Clone)]
+pub struct Collections {
+    pub(crate) pool: PgPool,
+}
+
+pub enum CollectionWrite {
+    Written(Collection),
+    /// The collection changed on the server since the client last saw it.
+    Stale(Collection),
+    /// The id belongs to another user's collection.
+    Forbidden,
+    Invalid(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeleteMode {
+    /// Deletes everything in the collection along with it.
+    Trash,
+    /// Moves the collection's children and bookmarks up to its parent.
+    Reparent,
+}
+
+impl Collections {
+    /// Writes the collections with the same version checks as bookmarks,
+    /// rejecting moves under a missing or deleted parent and moves that would
+    /// create a cycle. Deleting a collection deletes everything in it.
+    pub async fn bulk_upsert(
+        &self,
+        user_id: &str,
+        origin: Option<&str>,
+        collections: &[Collection],
+    ) -> anyhow::Result<Vec<CollectionWrite>> {
+        if collections.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .context("error beginning transaction")?;
+
+        lock_user(&mut tx, user_id).await?;
+
+        let mut tree = get_tree(&mut tx, user_id).await?;
+
+        let ids = collections
+            .iter()
+            .map(|c| c.id.to_owned())
+            .collect::<Vec<_>>();
+
+        let foreign = query_scalar!(
+            r#"SELECT id FROM collections WHERE id = ANY($1) AND user_id <> $2"#,
+            &ids,
+            user_id,
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .context("error getting foreign collections")?
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+        let mut outcomes = collections
+            .iter()
+            .map(|collection| {
+                if foreign.contains(&collection.id) {
+                    Some(CollectionWrite::Forbidden)
+                } else {
+                    tree.get(&collection.id)
+                        .filter(|existing| existing.version != collection.version)
+                        .map(|existing| CollectionWrite::Stale(existing.clone()))
+                }
+            })
+            .collect::<Vec<_>>();
+
+        // a parent can come later in the batch than its children, so rows
+        // that fail the parent check are retried until no more of them pass
+        let mut accepted = vec![false; collections.len()];
+
+        loop {
+            let mut progressed = false;
+
+            for (i, collection) in collections.iter().enumerate() {
+                if accepted[i] || outcomes[i].is_some() {
+                    continue;
+                }
+
+                let previous = tree.insert(collection.id.to_owned(), collection.clone());
+
+                if check_parent(&tree, collection).is_ok() {
+                    accepted[i] = true;
+                    progressed = true;
+                } else {
+                    match previous {
+                        Some(previous) => tree.insert(collection.id.to_owned(), previous),
+                        None => tree.remove(&collection.id),
+                    };
+                }
+            }
+
+            if !progressed {
+                break;
+            }
+        }
+
+        let mut changed = vec![];
+
+        for (i, collection) in collections.iter().enumerate() {
+            if accepted[i] {
+                changed.push(collection.clone());
+            } else if outcomes[i].is_none() {
+                let err = check_parent(&tree, collection).err().unwrap_or_default();
+                outcomes[i] = Some(CollectionWrite::Invalid(err));
+            }
+        }
+
+        let written = save(&mut tx, user_id, origin, &mut tree, changed)
+            .await?
+            .into_iter()
+            .map(|c| (c.id.to_owned(), c))
+            .collect::<HashMap<_, _>>();
+
+        tx.commit().await.context("error committing transaction")?;
+
+        let outcomes = outcomes
+            .into_iter()
+            .zip(collections)
+            .map(|(outcome, collection)| {
+                outcome.unwrap_or_else(|| match written.get(&collection.id) {
+                    Some(written) => CollectionWrite::Written(written.clone()),
+                    // lost a race for the id against another user
+                    None => CollectionWrite::Forbidden,
+                })
+            })
+            .collect();
+
+        Ok(outcomes)
+    }
+
+    /// Deletes the user's collection, returns `None` when there's no such
+    /// collection that isn't deleted yet.
+    pub async fn delete(
+        &self,
+        user_id: &str,
+        origin: Option<&str>,
+        id: &str,
+        mode: DeleteMode,
+    ) -> anyhow::Result<Option<Collection>> {
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .context("error beginning transaction")?;
+
+        lock_user(&mut tx, user_id).await?;
+
+        let mut tree = get_tree(&mut tx, user_id).await?;
+
+        let Some(collection) = tree.get(id).filter(|c| c.deleted_at.is_none()).cloned() else {
+            return Ok(None);
+        };
+
+        let mut changed = vec![];
+
+        if mode == DeleteMode::Reparent {
+            for child in tree.values() {
+                if child.parent_id.as_deref() == Some(id) && child.deleted_at.is_none() {
+                    changed.push(Collection {
+                        parent_id: collection.parent_id.to_owned(),
+                        updated_at: Utc::now(),
+                        ..child.clone()
+                    });
+                }
+            }
+
+            let moved = query_scalar!(
+                r#"
+                SELECT id FROM bookmarks
+                WHERE user_id = $1 AND collection_id = $2
+                AND deleted_at IS NULL
+                "#,
+                user_id,
+                id,
+            )
+            .fetch_all(&mut *tx)
+            .await
+            .context("error getting bookmarks to move")?;
+
+            let matched_before = get_matches(&mut tx, user_id, &moved)
+                .await
+                .context("error matching saved searches")?;
+
+            query!(
+                r#"UPDATE bookmarks SET collection_id = $2 WHERE id = ANY($1)"#,
+                &moved,
+                collection.parent_id,
+            )
+            .execute(&mut *tx)
+            .await
+            .context("error moving bookmarks")?;
+
+            bump_versions(&mut tx, user_id, origin, &moved)
+                .await
+                .context("error bumping bookmark versions")?;
+
+            announce_new_matches(&mut tx, user_id, &matched_before, &moved)
+                .await
+                .context("error announcing saved search matches")?;
+        }
+
+        changed.push(Collection {
+            deleted_at: Some(Utc::now()),
+            updated_at: Utc::now(),
+            ..collection
+        });
+
+        let deleted = save(&mut tx, user_id, origin, &mut tree, changed)
+            .await?
+            .into_iter()
+            .find(|c| c.id == id);
+
+        tx.commit().await.context("error committing transaction")?;
+
+        Ok(deleted)
+    }
+
+    /// The user's collections that aren't deleted.
+    pub async fn get_all(&self, user_id: &str) -> anyhow::Result<Vec<Collection>> {
+        let collections = query_as!(
+            Collection,
+            r#"
+            SELECT id, parent_id, name, position, updated_at, deleted_at, version
+            FROM collections
+            WHERE user_id = $1
+            AND deleted_at IS NULL
+            ORDER BY position, id
+            "#,
+            user_id,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(collections)
+    }
+
+    /// Ids among `ids` of the user's collections that aren't deleted.
+    pub async fn get_live_ids(&self, user_id: &str, ids: &[String]) -> anyhow::Result<Vec<String>> {
+        let ids = query_scalar!(
+            r#"
+            SELECT id FROM collections
+            WHERE user_id = $1 AND id = ANY($2) AND deleted_at IS NULL
+            "#,
+            user_id,
+            ids,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(ids)
+    }
+
+    /// Same as [`super::Bookmarks::purge_tombstones`] for collections.
+    pub async fn purge_tombstones(&self, before: &DateTime<Utc>) -> anyhow::Result<i64> {
+        let purged = query_scalar!(
+            r#"
+            WITH purged AS (
+                DELETE FROM collections col
+                USING changes c
+                WHERE c.collection_id = col.id
+                AND c.seq = col.version
+                AND c.op = 'delete'
+                AND c.created_at < $1
+                RETURNING col.id, col.user_id, col.version
+            ), purged_changes AS (
+                DELETE FROM changes
+                WHERE collection_id IN (SELECT id FROM purged)
+            ), watermarks AS (
+                UPDATE users
+                SET purged_seq = GREATEST(users.purged_seq, p.max_version)
+                FROM (
+                    SELECT user_id, MAX(version) AS max_version
+                    FROM purged
+                    GROUP BY user_id
+                ) p
+                WHERE users.id = p.user_id
+            )
+            SELECT COUNT(*) as "count!" FROM purged
+            "#,
+            before,
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(purged)
+    }
+}
+
+async fn get_tree(
+    conn: &mut PgConnection,
+    user_id: &str,
+) -> anyhow::Result<HashMap<String, Collection>> {
+    let collections = query_as!(
+        Collection,
+        r#"
+        SELECT id, parent_id, name, position, updated_at, deleted_at, version
+        FROM collections
+        WHERE user_id = $1
+        "#,
+        user_id,
+    )
+    .fetch_all(&mut *conn)
+    .await
+    .context("error getting collections")?;
+
+    Ok(collections
+        .into_iter()
+        .map(|c| (c.id.to_owned(), c))
+        .collect())
+}
+
+fn check_parent(tree: &HashMap<String, Collection>, collection: &Collection) -> Result<(), String> {
+    // deleted collections keep their last parent, whatever it is now
+    if collection.deleted_at.is_some() {
+        return Ok(());
+    }
+
+    let mut parent_id = collection.parent_id.as_ref();
+
+    while let Some(id) = parent_id {
+        if *id == collection.id {
+            return Err("collection can't be moved into itself".to_owned());
+        }
+
+        match tree.get(id) {
+            Some(parent) if parent.deleted_at.is_none() => parent_id = parent.parent_id.as_ref(),
+            _ => return Err("parent collection not found".to_owned()),
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether a missing or deleted collection is above the collection.
+fn is_orphaned(tree: &HashMap<String, Collection>, collection: &Collection) -> bool {
+    let mut parent_id = collection.parent_id.as_ref();
+
+    // a cycle can't be reached through the checks, but bounding the walk
+    // keeps a broken tree from hanging it
+    for _ in 0..=tree.len() {
+        let Some(id) = parent_id else {
+            return false;
+        };
+
+        match tree.get(id) {
+            Some(parent) if parent.deleted_at.is_none() => parent_id = parent.parent_id.as_ref(),
+            _ => return true,
+        }
+    }
+
+    true
+}
+
+/// Writes the changed collections with new versions and change log entries,
+/// after deleting everything below deleted collections along with them. The
+/// written collections are returned.
+async fn save(
+    conn: &mut PgConnection,
+    user_id: &str,
+    origin: Option<&str>,
+    tree: &mut HashMap<String, Collection>,
+    changed: Vec<Collection>,
+) -> anyhow::Result<Vec<Collection>> {
+    for collection in &changed {
+        tree.insert(collection.id.to_owned(), collection.clone());
+    }
+
+    let now = Utc::now();
+
+    let orphaned = tree
+        .values()
+        .filter(|c| c.deleted_at.is_none() && is_orphaned(tree, c))
+        .map(|c| c.id.to_owned())
+        .collect::<Vec<_>>();
+
+    for id in &orphaned {
+        if let Some(collection) = tree.get_mut(id) {
+            collection.deleted_at = Some(now);
+            collection.updated_at = now;
+        }
+    }
+
+    let mut ids = changed.iter().map(|c| c.id.to_owned()).collect::<Vec<_>>();
+
+    for id in orphaned {
+        if !ids.contains(&id) {
+            ids.push(id);
+        }
+    }
+
+    if ids.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let deleted_ids = ids
+        .iter()
+        .filter(|id| tree[*id].deleted_at.is_some())
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let trashed = query_scalar!(
+        r#"
+        UPDATE bookmarks SET deleted_at = now()
+        WHERE user_id = $1 AND deleted_at IS NULL AND collection_id = ANY($2)
+        RETURNING id
+        "#,
+        user_id,
+        &deleted_ids,
+    )
+    .fetch_all(&mut *conn)
+    .await
+    .context("error trashing bookmarks")?;
+
+    bump_versions(conn, user_id, origin, &trashed)
+        .await
+        .context("error bumping bookmark versions")?;
+
+    let last_version = query_scalar!(
+        r#"
+        UPDATE users SET sync_version = sync_version + $2
+        WHERE id = $1
+        RETURNING sync_version
+        "#,
+        user_id,
+        ids.len() as i64,
+    )
+    .fetch_one(&mut *conn)
+    .await
+    .context("error bumping sync version")?;
+
+    let first_version = last_version - ids.len() as i64 + 1;
+
+    let mut parent_ids = Vec::with_capacity(ids.len());
+    let mut names = Vec::with_capacity(ids.len());
+    let mut positions = Vec::with_capacity(ids.len());
+    let mut updated_ats = Vec::with_capacity(ids.len());
+    let mut deleted_ats = Vec::with_capacity(ids.len());
+    let mut versions = Vec::with_capacity(ids.len());
+
+    for (i, id) in ids.iter().enumerate() {
+        let collection = &tree[id];
+
+        parent_ids.push(collection.parent_id.to_owned());
+        names.push(collection.name.to_owned());
+        positions.push(collection.position);
+        updated_ats.push(collection.updated_at);
+        deleted_ats.push(collection.deleted_at);
+        versions.push(first_version + i as i64);
+    }
+
+    let written = query_as!(
+        Collection,
+        r#"
+        WITH written AS (
+            INSERT INTO collections (id, parent_id, name, position, updated_at, deleted_at, version, user_id)
+            SELECT *, $1
+            FROM UNNEST(
+                $2::text[],
+                $3::text[],
+                $4::text[],
+                $5::int8[],
+                $6::timestamptz[],
+                $7::timestamptz[],
+                $8::int8[]
+            )
+            ON CONFLICT (id) DO UPDATE SET
+                parent_id = EXCLUDED.parent_id,
+                name = EXCLUDED.name,
+                position = EXCLUDED.position,
+                updated_at = EXCLUDED.updated_at,
+                deleted_at = EXCLUDED.deleted_at,
+                version = EXCLUDED.version
+            WHERE collections.user_id = EXCLUDED.user_id
+            RETURNING id, parent_id, name, position, updated_at, deleted_at, version, user_id
+        ), logged AS (
+            INSERT INTO changes (user_id, seq, collection_id, op)
+            SELECT user_id, version, id, CASE WHEN deleted_at IS NULL THEN 'upsert' ELSE 'delete' END
+            FROM written
+        )
+        SELECT
+            id as "id!",
+            parent_id,
+            name as "name!",
+            position as "position!",
+            updated_at as "updated_at!",
+            deleted_at,
+            version as "version!"
+        FROM written
+        "#,
+        user_id,
+        &ids,
+        &parent_ids as &[Option<String>],
+        &names,
+        &positions,
+        &updated_ats,
+        &deleted_ats as &[Option<DateTime<Utc>>],
+        &versions,
+    )
+    .fetch_all(&mut *conn)
+    .await
+    .context("error upserting collections")?;
+
+    notify_changes(
+        conn,
+        &ChangeNotification {
+            user_id: user_id.to_owned(),
+            after_seq: first_version - 1,
+            last_seq: last_version,
+            origin: origin.map(str::to_owned),
+        },
+    )
+    .await
+    .context("error notifying changes")?;
+
+    Ok(written)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Collection {
+    pub id: String,
+    /// `None` for collections at the root.
+    #[serde(default)]
+    pub parent_id: Option<String>,
+    pub name: String,
+    /// Orders the collection among the other items of its parent.
+    #[serde(default)]
+    pub position: i64,
+    pub updated_at: DateTime<Utc>,
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// Same as [`super::Bookmark::version`].
+    #[serde(default)]
+    pub version: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collection(id: &str, parent_id: Option<&str>) -> Collection {
+        Collection {
+            id: id.to_owned(),
+            parent_id: parent_id.map(str::to_owned),
+            name: id.to_owned(),
+            position: 0,
+            updated_at: Utc::now(),
+            deleted_at: None,
+            version: 0,
+        }
+    }
+
+    fn tree<const N: usize>(collections: [Collection; N]) -> HashMap<String, Collection> {
+        collections
+            .into_iter()
+            .map(|c| (c.id.to_owned(), c))
+            .collect()
+    }
+
+    #[test]
+    fn rejects_self_parent() {
+        let a = collection("a", Some("a"));
+
+        assert!(check_parent(&tree([a.clone()]), &a).is_err());
+    }
+
+    #[test]
+    fn rejects_cycles() {
+        let a = collection("a", Some("b"));
+        let b = collection("b", Some("a"));
+        let tree = tree([a.clone(), b]);
+
+        assert!(check_parent(&tree, &a).is_err());
+        assert!(is_orphaned(&tree, &a));
+    }
+
+    #[test]
+    fn accepts_parent_from_same_batch() {
+        let child = collection("child", Some("parent"));
+        let parent = collection("parent", None);
+
+        // the child comes first and fails until its parent is in the tree
+        let mut tree = tree([child.clone()]);
+        assert!(check_parent(&tree, &child).is_err());
+
+        tree.insert(parent.id.to_owned(), parent);
+        assert!(check_parent(&tree, &child).is_ok());
+        assert!(!is_orphaned(&tree, &child));
+    }
+
+    #[test]
+    fn rejects_deleted_parent() {
+        let parent = Collection {
+            deleted_at: Some(Utc::now()),
+            ..collection("parent", None)
+        };
+        let child = collection("child", Some("parent"));
+        let grandchild = collection("grandchild", Some("child"));
+        let tree = tree([parent, child.clone(), grandchild.clone()]);
+
+        assert!(check_parent(&tree, &child).is_err());
+        assert!(is_orphaned(&tree, &child));
+        assert!(is_orphaned(&tree, &grandchild));
+    }
+}
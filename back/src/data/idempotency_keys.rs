@@ -0,0 +1,118 @@
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use sqlx::{query, query_scalar, PgPool};
+
+#[derive(Clone)]
+pub struct IdempotencyKeys {
+    pub(crate) pool: PgPool,
+}
+
+pub enum Reservation {
+    /// The caller got the key and has to complete or release it.
+    Reserved,
+    /// Another request with the key is still running.
+    Pending,
+    /// The response of the request that had the key first.
+    Done(Value),
+    /// The key was first used for a different request.
+    Mismatch,
+}
+
+impl IdempotencyKeys {
+    /// Claims the key for a request. Keys created before `created_after` have
+    /// expired and are claimed again, as are keys still pending since before
+    /// `pending_after`, whose request must have died.
+    pub async fn reserve(
+        &self,
+        user_id: &str,
+        key: &str,
+        request_hash: &str,
+        created_after: &DateTime<Utc>,
+        pending_after: &DateTime<Utc>,
+    ) -> anyhow::Result<Reservation> {
+        let reserved = query_scalar!(
+            r#"
+            insert into idempotency_keys (user_id, key, request_hash, response)
+            values ($1, $2, $5, null)
+            on conflict (user_id, key)
+            do update set request_hash = excluded.request_hash, response = null, created_at = now()
+            where idempotency_keys.created_at <= $3
+            or (idempotency_keys.response is null and idempotency_keys.created_at <= $4)
+            returning key;
+            "#,
+            user_id,
+            key,
+            created_after,
+            pending_after,
+            request_hash,
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        if reserved.is_some() {
+            return Ok(Reservation::Reserved);
+        }
+
+        let existing = query!(
+            r#"select request_hash, response from idempotency_keys where user_id = $1 and key = $2;"#,
+            user_id,
+            key,
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(match existing {
+            Some(existing) if existing.request_hash != request_hash => Reservation::Mismatch,
+            Some(existing) => match existing.response {
+                Some(response) => Reservation::Done(response),
+                None => Reservation::Pending,
+            },
+            // released in the meantime counts as pending too, a retry claims it
+            None => Reservation::Pending,
+        })
+    }
+
+    /// Stores the response of the request that reserved the key.
+    pub async fn complete(&self, user_id: &str, key: &str, response: &Value) -> anyhow::Result<()> {
+        query!(
+            r#"
+            update idempotency_keys set response = $3
+            where user_id = $1 and key = $2;
+            "#,
+            user_id,
+            key,
+            response,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Gives up a reservation after the request failed, so a retry can run.
+    pub async fn release(&self, user_id: &str, key: &str) -> anyhow::Result<()> {
+        query!(
+            r#"
+            delete from idempotency_keys
+            where user_id = $1 and key = $2 and response is null;
+            "#,
+            user_id,
+            key,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn purge(&self, before: &DateTime<Utc>) -> anyhow::Result<u64> {
+        let result = query!(
+            r#"delete from idempotency_keys where created_at < $1;"#,
+            before,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+}
@@ -6,21 +6,54 @@ use sqlx::{migrate, PgPool};
 mod bookmarks;
 pub use bookmarks::*;
 
+mod changes;
+pub use changes::*;
+
+mod collections;
+pub use collections::*;
+
+mod idempotency_keys;
+pub use idempotency_keys::*;
+
+mod notifications;
+pub use notifications::*;
+
+mod saved_searches;
+pub use saved_searches::*;
+
+mod search;
+pub use search::*;
+
 mod sessions;
 pub use sessions::*;
 
+mod tags;
+pub use tags::*;
+
 mod users;
 pub use users::*;
 
 #[derive(Clone)]
 pub struct Data {
     pub bookmarks: Bookmarks,
+    pub changes: Changes,
+    pub collections: Collections,
+    pub idempotency_keys: IdempotencyKeys,
+    pub notifications: Notifications,
+    pub saved_searches: SavedSearches,
     pub sessions: Sessions,
+    pub tags: Tags,
     pub users: Users,
 }
 struct Postgres {
     pub(crate) bookmarks: Bookmarks,
+    pub(crate) changes: Changes,
+    pub(crate) collections: Collections,
+    pub(crate) idempotency_keys: IdempotencyKeys,
+    pub(crate) notifications: Notifications,
+    pub(crate) saved_searches: SavedSearches,
     pub(crate) sessions: Sessions,
+    pub(crate) tags: Tags,
     pub(crate) users: Users,
 }
 
@@ -35,22 +68,51 @@ impl Data {
             .await
             .context("error running postgres migrations")?;
 
+        Ok(Self::from_pool(postgres_pool))
+    }
+
+    /// For a pool that's already migrated.
+    pub fn from_pool(postgres_pool: PgPool) -> Self {
         let postgres = Postgres {
             bookmarks: Bookmarks {
                 pool: postgres_pool.clone(),
             },
+            changes: Changes {
+                pool: postgres_pool.clone(),
+            },
+            collections: Collections {
+                pool: postgres_pool.clone(),
+            },
+            idempotency_keys: IdempotencyKeys {
+                pool: postgres_pool.clone(),
+            },
+            notifications: Notifications {
+                pool: postgres_pool.clone(),
+            },
+            saved_searches: SavedSearches {
+                pool: postgres_pool.clone(),
+            },
             sessions: Sessions {
                 pool: postgres_pool.clone(),
             },
+            tags: Tags {
+                pool: postgres_pool.clone(),
+            },
             users: Users {
                 pool: postgres_pool.clone(),
             },
         };
 
-        return Ok(Self {
+        Self {
             bookmarks: postgres.bookmarks,
+            changes: postgres.changes,
+            collections: postgres.collections,
+            idempotency_keys: postgres.idempotency_keys,
+            notifications: postgres.notifications,
+            saved_searches: postgres.saved_searches,
             sessions: postgres.sessions,
+            tags: postgres.tags,
             users: postgres.users,
-        });
+        }
     }
 }
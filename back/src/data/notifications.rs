@@ -0,0 +1,33 @@
+use sqlx::{query, PgConnection, PgPool};
+
+/// Carries [`super::ChangeNotification`]s.
+pub const CHANGES_CHANNEL: &str = "changes";
+
+/// Carries [`super::MatchNotification`]s.
+pub const MATCHES_CHANNEL: &str = "matches";
+
+/// Carries events that aren't backed by the change log.
+pub const EVENTS_CHANNEL: &str = "events";
+
+#[derive(Clone)]
+pub struct Notifications {
+    pub(crate) pool: PgPool,
+}
+
+impl Notifications {
+    pub async fn notify(&self, channel: &str, payload: &str) -> anyhow::Result<()> {
+        let mut conn = self.pool.acquire().await?;
+
+        notify(&mut conn, channel, payload).await
+    }
+}
+
+/// Postgres delivers the notification once the surrounding transaction
+/// commits, so listeners never hear about writes that end up rolled back.
+pub async fn notify(conn: &mut PgConnection, channel: &str, payload: &str) -> anyhow::Result<()> {
+    query!(r#"select pg_notify($1, $2)"#, channel, payload)
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,474 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_as, query_scalar, PgConnection, PgPool};
+use tracing::warn;
+
+use super::{matching_ids, notify, notify_changes, ChangeNotification, MATCHES_CHANNEL};
+use crate::query::parse;
+
+// ids are at most 30 characters, so this many stay well under the 8000 byte
+// notification payload limit
+const MAX_MATCHES_PER_NOTIFICATION: usize = 100;
+
+/// Published on [`super::MATCHES_CHANNEL`] when a write makes bookmarks match
+/// a saved search they didn't match before. Only carries ids to stay under
+/// the notification payload limit, listeners load the bookmarks.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MatchNotification {
+    pub user_id: String,
+    pub saved_search_id: String,
+    pub bookmark_ids: Vec<String>,
+}
+
+/// Ids of the bookmarks each saved search matches, by saved search id.
+pub type Matches = HashMap<String, HashSet<String>>;
+
+#[derive(Clone)]
+pub struct SavedSearches {
+    pub(crate) pool: PgPool,
+}
+
+impl SavedSearches {
+    /// Writes the saved searches with the same version checks and change log
+    /// entries as [`super::Bookmarks::bulk_upsert`], the written rows are
+    /// returned.
+    pub async fn bulk_upsert(
+        &self,
+        user_id: &str,
+        origin: Option<&str>,
+        saved_searches: &[SavedSearch],
+    ) -> anyhow::Result<Vec<SavedSearch>> {
+        if saved_searches.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .context("error beginning transaction")?;
+
+        // also locks the user row, see bookmarks
+        let last_version = query_scalar!(
+            r#"
+            UPDATE users SET sync_version = sync_version + $2
+            WHERE id = $1
+            RETURNING sync_version
+            "#,
+            user_id,
+            saved_searches.len() as i64,
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .context("error bumping sync version")?;
+
+        let first_version = last_version - saved_searches.len() as i64 + 1;
+
+        let mut ids = Vec::with_capacity(saved_searches.len());
+        let mut names = Vec::with_capacity(saved_searches.len());
+        let mut queries = Vec::with_capacity(saved_searches.len());
+        let mut positions = Vec::with_capacity(saved_searches.len());
+        let mut updated_ats = Vec::with_capacity(saved_searches.len());
+        let mut deleted_ats = Vec::with_capacity(saved_searches.len());
+        let mut versions = Vec::with_capacity(saved_searches.len());
+        let mut base_versions = Vec::with_capacity(saved_searches.len());
+
+        for (i, saved_search) in saved_searches.iter().enumerate() {
+            ids.push(saved_search.id.to_owned());
+            names.push(saved_search.name.to_owned());
+            queries.push(saved_search.query.to_owned());
+            positions.push(saved_search.position);
+            updated_ats.push(saved_search.updated_at);
+            deleted_ats.push(saved_search.deleted_at);
+            versions.push(first_version + i as i64);
+            base_versions.push(saved_search.version);
+        }
+
+        let written = query_as!(
+            SavedSearch,
+            r#"
+            WITH incoming AS (
+                SELECT *
+                FROM UNNEST(
+                    $2::text[],
+                    $3::text[],
+                    $4::text[],
+                    $5::int8[],
+                    $6::timestamptz[],
+                    $7::timestamptz[],
+                    $8::int8[],
+                    $9::int8[]
+                ) AS t(id, name, query, position, updated_at, deleted_at, version, base_version)
+            ), allowed AS (
+                SELECT incoming.*
+                FROM incoming
+                LEFT JOIN saved_searches existing ON existing.id = incoming.id
+                WHERE existing.id IS NULL
+                OR (existing.user_id = $1 AND existing.version = incoming.base_version)
+            ), written AS (
+                INSERT INTO saved_searches (id, name, query, position, updated_at, deleted_at, version, user_id)
+                SELECT id, name, query, position, updated_at, deleted_at, version, $1
+                FROM allowed
+                ON CONFLICT (id) DO UPDATE SET
+                    name = EXCLUDED.name,
+                    query = EXCLUDED.query,
+                    position = EXCLUDED.position,
+                    updated_at = EXCLUDED.updated_at,
+                    deleted_at = EXCLUDED.deleted_at,
+                    version = EXCLUDED.version
+                WHERE saved_searches.user_id = EXCLUDED.user_id
+                RETURNING id, name, query, position, updated_at, deleted_at, version, user_id
+            ), logged AS (
+                INSERT INTO changes (user_id, seq, saved_search_id, op)
+                SELECT user_id, version, id, CASE WHEN deleted_at IS NULL THEN 'upsert' ELSE 'delete' END
+                FROM written
+            )
+            SELECT
+                id as "id!",
+                name as "name!",
+                query as "query!",
+                position as "position!",
+                updated_at as "updated_at!",
+                deleted_at,
+                version as "version!"
+            FROM written
+            "#,
+            user_id,
+            &ids,
+            &names,
+            &queries,
+            &positions,
+            &updated_ats,
+            &deleted_ats as &[Option<DateTime<Utc>>],
+            &versions,
+            &base_versions,
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .context("error upserting saved searches")?;
+
+        if !written.is_empty() {
+            notify_changes(
+                &mut tx,
+                &ChangeNotification {
+                    user_id: user_id.to_owned(),
+                    after_seq: first_version - 1,
+                    last_seq: last_version,
+                    origin: origin.map(str::to_owned),
+                },
+            )
+            .await
+            .context("error notifying changes")?;
+        }
+
+        tx.commit().await.context("error committing transaction")?;
+
+        Ok(written)
+    }
+
+    /// The user's saved search, `None` when there's no such saved search that
+    /// isn't deleted.
+    pub async fn get(&self, user_id: &str, id: &str) -> anyhow::Result<Option<SavedSearch>> {
+        let saved_search = query_as!(
+            SavedSearch,
+            r#"
+            SELECT id, name, query, position, updated_at, deleted_at, version
+            FROM saved_searches
+            WHERE user_id = $1
+            AND id = $2
+            AND deleted_at IS NULL
+            "#,
+            user_id,
+            id,
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(saved_search)
+    }
+
+    /// The user's saved searches that aren't deleted.
+    pub async fn get_all(&self, user_id: &str) -> anyhow::Result<Vec<SavedSearch>> {
+        let saved_searches = query_as!(
+            SavedSearch,
+            r#"
+            SELECT id, name, query, position, updated_at, deleted_at, version
+            FROM saved_searches
+            WHERE user_id = $1
+            AND deleted_at IS NULL
+            ORDER BY position, id
+            "#,
+            user_id,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(saved_searches)
+    }
+
+    pub async fn get_many(
+        &self,
+        user_id: &str,
+        ids: &[String],
+    ) -> anyhow::Result<Vec<SavedSearch>> {
+        let saved_searches = query_as!(
+            SavedSearch,
+            r#"
+            SELECT id, name, query, position, updated_at, deleted_at, version
+            FROM saved_searches
+            WHERE user_id = $1
+            AND id = ANY($2)
+            "#,
+            user_id,
+            ids,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(saved_searches)
+    }
+
+    /// Same as [`super::Bookmarks::purge_tombstones`] for saved searches.
+    pub async fn purge_tombstones(&self, before: &DateTime<Utc>) -> anyhow::Result<i64> {
+        let purged = query_scalar!(
+            r#"
+            WITH purged AS (
+                DELETE FROM saved_searches s
+                USING changes c
+                WHERE c.saved_search_id = s.id
+                AND c.seq = s.version
+                AND c.op = 'delete'
+                AND c.created_at < $1
+                RETURNING s.id, s.user_id, s.version
+            ), purged_changes AS (
+                DELETE FROM changes
+                WHERE saved_search_id IN (SELECT id FROM purged)
+            ), watermarks AS (
+                UPDATE users
+                SET purged_seq = GREATEST(users.purged_seq, p.max_version)
+                FROM (
+                    SELECT user_id, MAX(version) AS max_version
+                    FROM purged
+                    GROUP BY user_id
+                ) p
+                WHERE users.id = p.user_id
+            )
+            SELECT COUNT(*) as "count!" FROM purged
+            "#,
+            before,
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(purged)
+    }
+}
+
+/// Which of the bookmarks with the ids each of the user's saved searches
+/// matches. Write paths take them before writing and pass them to
+/// [`announce_new_matches`] after, on the same transaction.
+pub(super) async fn get_matches(
+    conn: &mut PgConnection,
+    user_id: &str,
+    ids: &[String],
+) -> anyhow::Result<Matches> {
+    let mut matches = Matches::new();
+
+    if ids.is_empty() {
+        return Ok(matches);
+    }
+
+    let saved_searches = query!(
+        r#"
+        SELECT id, query
+        FROM saved_searches
+        WHERE user_id = $1
+        AND deleted_at IS NULL
+        "#,
+        user_id,
+    )
+    .fetch_all(&mut *conn)
+    .await
+    .context("error getting saved searches")?;
+
+    let mut searches = Vec::with_capacity(saved_searches.len());
+
+    for saved_search in saved_searches {
+        // queries are checked when they're synced, but the grammar may have
+        // changed since
+        match parse(&saved_search.query) {
+            Ok(query) => searches.push((saved_search.id, query)),
+            Err(err) => warn!("invalid saved search {}: {}", saved_search.id, err),
+        }
+    }
+
+    for (saved_search_id, bookmark_id) in matching_ids(conn, user_id, &searches, ids).await? {
+        matches
+            .entry(saved_search_id)
+            .or_default()
+            .insert(bookmark_id);
+    }
+
+    Ok(matches)
+}
+
+/// Tells the user's subscribers about bookmarks with the ids that match a
+/// saved search but didn't `before` the write, with one notification per
+/// saved search.
+pub(super) async fn announce_new_matches(
+    conn: &mut PgConnection,
+    user_id: &str,
+    before: &Matches,
+    ids: &[String],
+) -> anyhow::Result<()> {
+    let after = get_matches(conn, user_id, ids).await?;
+
+    for (saved_search_id, matched) in after {
+        let mut new = matched
+            .into_iter()
+            .filter(|id| {
+                !before
+                    .get(&saved_search_id)
+                    .is_some_and(|matched| matched.contains(id))
+            })
+            .collect::<Vec<_>>();
+
+        new.sort();
+
+        for bookmark_ids in new.chunks(MAX_MATCHES_PER_NOTIFICATION) {
+            let payload = serde_json::to_string(&MatchNotification {
+                user_id: user_id.to_owned(),
+                saved_search_id: saved_search_id.to_owned(),
+                bookmark_ids: bookmark_ids.to_vec(),
+            })?;
+
+            notify(conn, MATCHES_CHANNEL, &payload)
+                .await
+                .context("error notifying saved search matches")?;
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SavedSearch {
+    pub id: String,
+    pub name: String,
+    /// In the search query language, see [`crate::query::parse`].
+    pub query: String,
+    /// Orders the saved search among the user's other saved searches.
+    #[serde(default)]
+    pub position: i64,
+    pub updated_at: DateTime<Utc>,
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// Same as [`super::Bookmark::version`].
+    #[serde(default)]
+    pub version: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use sqlx::postgres::PgListener;
+
+    use super::*;
+    use crate::{
+        data::{Bookmark, Data},
+        testing::{bookmark, user},
+    };
+
+    async fn saved_search(data: &Data, user_id: &str, query: &str) -> String {
+        let saved_search: SavedSearch = serde_json::from_value(json!({
+            "id": crate::id::new_id(),
+            "name": query,
+            "query": query,
+            "updated_at": Utc::now(),
+            "deleted_at": null,
+        }))
+        .unwrap();
+
+        data.saved_searches
+            .bulk_upsert(user_id, None, std::slice::from_ref(&saved_search))
+            .await
+            .unwrap();
+
+        saved_search.id
+    }
+
+    async fn next_match(listener: &mut PgListener) -> MatchNotification {
+        let notification = listener.recv().await.unwrap();
+
+        serde_json::from_str(notification.payload()).unwrap()
+    }
+
+    #[sqlx::test]
+    async fn announces_new_matches_once_per_search(pool: PgPool) {
+        let mut listener = PgListener::connect_with(&pool).await.unwrap();
+        listener.listen(MATCHES_CHANNEL).await.unwrap();
+
+        let data = Data::from_pool(pool);
+        let user_id = user(&data).await;
+        let search_id = saved_search(&data, &user_id, "tag:rust").await;
+
+        let (a, b, other) = ("a".to_owned(), "b".to_owned(), "c".to_owned());
+
+        data.bookmarks
+            .bulk_upsert(
+                &user_id,
+                None,
+                &[
+                    bookmark(json!({ "id": a, "tags": ["rust"] })),
+                    bookmark(json!({ "id": b, "tags": ["rust"] })),
+                    bookmark(json!({ "id": other, "tags": ["go"] })),
+                ],
+            )
+            .await
+            .unwrap();
+
+        let notification = next_match(&mut listener).await;
+        assert_eq!(notification.saved_search_id, search_id);
+        assert_eq!(notification.bookmark_ids, [a.to_owned(), b]);
+
+        // renaming the tag makes the other bookmark match, the ones that
+        // already did aren't announced again
+        let go = data
+            .tags
+            .list(&user_id)
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|t| t.name == "go")
+            .unwrap();
+
+        data.tags
+            .rename(&user_id, None, &go.id, "rust")
+            .await
+            .unwrap();
+
+        let notification = next_match(&mut listener).await;
+        assert_eq!(notification.bookmark_ids, [other]);
+
+        // edits that keep a bookmark matching aren't announced either
+        let edited = data.bookmarks.get_many(&user_id, &[a]).await.unwrap();
+        data.bookmarks
+            .bulk_upsert(
+                &user_id,
+                None,
+                &[Bookmark {
+                    title: "edited".to_owned(),
+                    tags: None,
+                    ..edited[0].clone()
+                }],
+            )
+            .await
+            .unwrap();
+
+        let pending =
+            tokio::time::timeout(std::time::Duration::from_millis(200), listener.recv()).await;
+        assert!(pending.is_err());
+    }
+}
@@ -0,0 +1,394 @@
+use anyhow::Context;
+use chrono::{DateTime, NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_as, PgConnection, Postgres, QueryBuilder};
+
+use super::{Bookmark, Bookmarks};
+use crate::query::{Query, TermKind};
+
+/// Marks the start and end of matches in highlights, neither can be in titles
+/// or notes as they're control characters.
+pub const MATCH_START: char = '\u{2}';
+pub const MATCH_END: char = '\u{3}';
+
+// lower than the default of 0.6 so typos in short queries still match
+const WORD_SIMILARITY_THRESHOLD: &str = "0.3";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchSort {
+    /// Best matches first.
+    #[default]
+    Relevance,
+    /// Most visited first, weighted towards recent visits.
+    Frecency,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SearchHit {
+    pub bookmark: Bookmark,
+    /// What the hits are sorted by, relevance or frecency.
+    pub rank: f32,
+    pub frecency: f32,
+    /// Trigram similarity to the title or host, only set by fuzzy search.
+    pub similarity: Option<f32>,
+    /// The title with matches between [`MATCH_START`] and [`MATCH_END`].
+    pub title_highlight: String,
+    /// Fragments of the notes around matches, marked like the title.
+    pub notes_highlight: String,
+}
+
+impl Bookmarks {
+    /// Searches the user's bookmarks with a parsed query, best matches of the
+    /// words and phrases first. Deleted bookmarks only show up for
+    /// `is:deleted`. `after` is the rank and id of the last hit of the
+    /// previous page.
+    pub async fn search(
+        &self,
+        user_id: &str,
+        search: &Query,
+        sort: SearchSort,
+        after: Option<(f32, &str)>,
+        limit: i64,
+    ) -> anyhow::Result<Vec<SearchHit>> {
+        let title_options =
+            format!("StartSel={MATCH_START}, StopSel={MATCH_END}, HighlightAll=true");
+        let notes_options = format!(
+            "StartSel={MATCH_START}, StopSel={MATCH_END}, MaxFragments=2, MaxWords=20, MinWords=5"
+        );
+
+        let mut builder = QueryBuilder::<Postgres>::new("WITH q AS (SELECT ");
+
+        // the words and phrases that aren't negated rank and highlight hits
+        let text_terms = search
+            .terms
+            .iter()
+            .filter(|term| {
+                !term.negated && matches!(term.kind, TermKind::Word(_) | TermKind::Phrase(_))
+            })
+            .collect::<Vec<_>>();
+
+        if text_terms.is_empty() {
+            builder.push("NULL::tsquery");
+        }
+
+        let mut separated = builder.separated(" && ");
+
+        for term in text_terms {
+            match &term.kind {
+                TermKind::Word(word) => {
+                    separated.push("plainto_tsquery('english', ");
+                    separated.push_bind_unseparated(word.clone());
+                    separated.push_unseparated(")");
+                }
+                TermKind::Phrase(phrase) => {
+                    separated.push("phraseto_tsquery('english', ");
+                    separated.push_bind_unseparated(phrase.clone());
+                    separated.push_unseparated(")");
+                }
+                _ => {}
+            }
+        }
+
+        builder.push(
+            r#" AS q
+            ), matches AS (
+                SELECT b.*, bookmark_frecency(b.visit_count, b.recent_visits) AS frecency, q.q,
+                    "#,
+        );
+        builder.push(match sort {
+            SearchSort::Relevance => "coalesce(ts_rank(b.search_vector, q.q), 0)",
+            SearchSort::Frecency => "bookmark_frecency(b.visit_count, b.recent_visits)",
+        });
+        builder.push(
+            r#" AS rank
+                FROM bookmarks b, q
+                WHERE b.user_id = "#,
+        );
+        builder.push_bind(user_id.to_owned());
+
+        push_filters(&mut builder, search);
+
+        builder.push(
+            r#"
+            )
+            SELECT id, title, url, deleted_at, updated_at, version, bookmark_tag_names(id) as tags,
+                collection_id, position, notes, visit_count, recent_visits[1] as last_visited_at,
+                rank, frecency,
+                NULL::real as similarity,
+                coalesce(ts_headline('english', title, q, "#,
+        );
+        builder.push_bind(title_options);
+        builder.push("), title) as title_highlight, coalesce(ts_headline('english', notes, q, ");
+        builder.push_bind(notes_options);
+        builder.push(
+            r#"), '') as notes_highlight
+            FROM matches
+            "#,
+        );
+
+        if let Some((after_rank, after_id)) = after {
+            builder.push("WHERE rank < ");
+            builder.push_bind(after_rank);
+            builder.push(" OR (rank = ");
+            builder.push_bind(after_rank);
+            builder.push(" AND id > ");
+            builder.push_bind(after_id.to_owned());
+            builder.push(") ");
+        }
+
+        builder.push("ORDER BY rank DESC, id LIMIT ");
+        builder.push_bind(limit);
+
+        let rows = builder
+            .build_query_as::<SearchRow>()
+            .fetch_all(&self.pool)
+            .await
+            .context("error searching bookmarks")?;
+
+        Ok(rows.into_iter().map(SearchHit::from).collect())
+    }
+
+    /// Typo tolerant search on titles and url hosts by trigram similarity,
+    /// boosted for exact and prefix matches. Paginates like
+    /// [`Bookmarks::search`], hits aren't highlighted.
+    pub async fn fuzzy_search(
+        &self,
+        user_id: &str,
+        query: &str,
+        sort: SearchSort,
+        after: Option<(f32, &str)>,
+        limit: i64,
+    ) -> anyhow::Result<Vec<SearchHit>> {
+        let query = query.trim().to_lowercase();
+
+        let pattern = query
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_");
+
+        let (after_rank, after_id) = after.unzip();
+
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .context("error beginning transaction")?;
+
+        query!(
+            r#"SELECT set_config('pg_trgm.word_similarity_threshold', $1, true)"#,
+            WORD_SIMILARITY_THRESHOLD,
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .context("error setting similarity threshold")?;
+
+        let rows = query_as!(
+            SearchRow,
+            r#"
+            WITH matches AS (
+                SELECT b.*, GREATEST(
+                    word_similarity($2, lower(b.title)),
+                    word_similarity($2, coalesce(b.url_host, ''))
+                ) AS similarity
+                FROM bookmarks b
+                WHERE b.user_id = $1
+                AND b.deleted_at IS NULL
+                AND (
+                    $2 <% lower(b.title)
+                    OR $2 <% b.url_host
+                    OR lower(b.title) LIKE '%' || $3 || '%'
+                    OR b.url_host LIKE $3 || '%'
+                )
+            ), ranked AS (
+                SELECT *, CASE WHEN $7 THEN frecency ELSE similarity + CASE
+                    WHEN lower(title) = $2 OR url_host = $2 OR split_part(url_host, '.', 1) = $2 THEN 1.0
+                    WHEN lower(title) LIKE $3 || '%' OR url_host LIKE $3 || '%' THEN 0.5
+                    WHEN lower(title) LIKE '%' || $3 || '%' THEN 0.25
+                    ELSE 0
+                END END AS rank
+                FROM (
+                    SELECT *, bookmark_frecency(visit_count, recent_visits) AS frecency
+                    FROM matches
+                ) m
+            )
+            SELECT id, title, url, deleted_at, updated_at, version, bookmark_tag_names(id) as "tags!",
+                collection_id, position, notes, visit_count, recent_visits[1] as last_visited_at,
+                rank::real as "rank!",
+                frecency as "frecency!",
+                similarity,
+                title as title_highlight,
+                '' as "notes_highlight!"
+            FROM ranked
+            WHERE $4::real IS NULL
+            OR rank::real < $4
+            OR (rank::real = $4 AND id > $5)
+            ORDER BY rank::real DESC, id
+            LIMIT $6
+            "#,
+            user_id,
+            query,
+            pattern,
+            after_rank,
+            after_id,
+            limit,
+            sort == SearchSort::Frecency,
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .context("error searching bookmarks")?;
+
+        tx.commit().await.context("error committing transaction")?;
+
+        Ok(rows.into_iter().map(SearchHit::from).collect())
+    }
+}
+
+impl From<SearchRow> for SearchHit {
+    fn from(row: SearchRow) -> Self {
+        SearchHit {
+            bookmark: Bookmark {
+                id: row.id,
+                title: row.title,
+                url: row.url,
+                updated_at: row.updated_at,
+                deleted_at: row.deleted_at,
+                version: row.version,
+                tags: Some(row.tags),
+                collection_id: Some(row.collection_id),
+                position: Some(row.position),
+                notes: Some(row.notes),
+                visit_count: row.visit_count,
+                last_visited_at: row.last_visited_at,
+            },
+            rank: row.rank,
+            frecency: row.frecency,
+            similarity: row.similarity,
+            title_highlight: row.title_highlight,
+            notes_highlight: row.notes_highlight,
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct SearchRow {
+    id: String,
+    title: String,
+    url: String,
+    updated_at: DateTime<Utc>,
+    deleted_at: Option<DateTime<Utc>>,
+    version: i64,
+    tags: Vec<String>,
+    collection_id: Option<String>,
+    position: i64,
+    notes: String,
+    visit_count: i64,
+    last_visited_at: Option<DateTime<Utc>>,
+    rank: f32,
+    frecency: f32,
+    similarity: Option<f32>,
+    title_highlight: String,
+    notes_highlight: String,
+}
+
+/// Which of the user's bookmarks with the ids each of the queries matches,
+/// as pairs of the query's key and the bookmark's id. All queries run in one
+/// statement.
+pub(super) async fn matching_ids(
+    conn: &mut PgConnection,
+    user_id: &str,
+    searches: &[(String, Query)],
+    ids: &[String],
+) -> anyhow::Result<Vec<(String, String)>> {
+    if searches.is_empty() || ids.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut builder = QueryBuilder::<Postgres>::new("");
+
+    for (i, (key, search)) in searches.iter().enumerate() {
+        if i > 0 {
+            builder.push(" UNION ALL ");
+        }
+
+        builder.push("SELECT ");
+        builder.push_bind(key.to_owned());
+        builder.push("::text, b.id FROM bookmarks b WHERE b.id = ANY(");
+        builder.push_bind(ids.to_owned());
+        builder.push(") AND b.user_id = ");
+        builder.push_bind(user_id.to_owned());
+
+        push_filters(&mut builder, search);
+    }
+
+    let matches = builder
+        .build_query_as::<(String, String)>()
+        .fetch_all(conn)
+        .await
+        .context("error matching bookmarks")?;
+
+    Ok(matches)
+}
+
+/// Appends the query's terms to a where clause over bookmarks `b`.
+fn push_filters(builder: &mut QueryBuilder<'_, Postgres>, search: &Query) {
+    if !search.includes_deleted() {
+        builder.push(" AND b.deleted_at IS NULL");
+    }
+
+    for term in &search.terms {
+        builder.push(if term.negated { " AND NOT (" } else { " AND (" });
+        push_condition(builder, &term.kind);
+        builder.push(")");
+    }
+}
+
+fn push_condition(builder: &mut QueryBuilder<'_, Postgres>, kind: &TermKind) {
+    match kind {
+        TermKind::Word(word) => {
+            builder.push("b.search_vector @@ plainto_tsquery('english', ");
+            builder.push_bind(word.clone());
+            builder.push(")");
+        }
+        TermKind::Phrase(phrase) => {
+            builder.push("b.search_vector @@ phraseto_tsquery('english', ");
+            builder.push_bind(phrase.clone());
+            builder.push(")");
+        }
+        TermKind::Tag(tag) => {
+            builder.push(
+                "EXISTS (SELECT 1 FROM bookmark_tags bt JOIN tags t ON t.id = bt.tag_id \
+                WHERE bt.bookmark_id = b.id AND lower(t.name) = lower(",
+            );
+            builder.push_bind(tag.clone());
+            builder.push("))");
+        }
+        TermKind::Site(site) => {
+            // hosts are stored without www. so www.example.com matches too
+            let site = site.to_lowercase();
+            let site = site.strip_prefix("www.").unwrap_or(&site);
+
+            let pattern = site
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_");
+
+            builder.push("coalesce(b.url_host = ");
+            builder.push_bind(site.to_owned());
+            builder.push(" OR b.url_host LIKE '%.' || ");
+            builder.push_bind(pattern);
+            builder.push(", false)");
+        }
+        TermKind::Before(date) => {
+            builder.push("b.created_at < ");
+            builder.push_bind(date.and_time(NaiveTime::MIN).and_utc());
+        }
+        TermKind::After(date) => {
+            builder.push("b.created_at >= ");
+            builder.push_bind(date.and_time(NaiveTime::MIN).and_utc());
+        }
+        TermKind::Deleted => {
+            builder.push("b.deleted_at IS NOT NULL");
+        }
+    }
+}
@@ -26,7 +26,7 @@ impl Sessions {
         .fetch_optional(&self.pool)
         .await?;
 
-        return Ok(row);
+        Ok(row)
     }
 
     pub async fn insert(&self, session: &Session) -> anyhow::Result<()> {
@@ -43,7 +43,7 @@ impl Sessions {
         .execute(&self.pool)
         .await?;
 
-        return Ok(());
+        Ok(())
     }
 
     pub async fn delete(&self, user_id: &str, session_id: &str) -> anyhow::Result<()> {
@@ -58,6 +58,6 @@ impl Sessions {
         .execute(&self.pool)
         .await?;
 
-        return Ok(());
+        Ok(())
     }
 }
@@ -0,0 +1,203 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_as, query_scalar, PgPool};
+
+use super::{announce_new_matches, bump_versions, get_matches, lock_user};
+
+#[derive(Clone)]
+pub struct Tags {
+    pub(crate) pool: PgPool,
+}
+
+impl Tags {
+    /// The user's tags that are on at least one bookmark that isn't deleted.
+    pub async fn list(&self, user_id: &str) -> anyhow::Result<Vec<Tag>> {
+        let tags = query_as!(
+            Tag,
+            r#"
+            SELECT t.id, t.name, COUNT(*) as "count!"
+            FROM tags t
+            JOIN bookmark_tags bt ON bt.tag_id = t.id
+            JOIN bookmarks b ON b.id = bt.bookmark_id
+            WHERE t.user_id = $1
+            AND b.deleted_at IS NULL
+            GROUP BY t.id
+            ORDER BY t.name COLLATE "C"
+            "#,
+            user_id,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(tags)
+    }
+
+    /// Renames the tag on all of its bookmarks at once. Renaming to the name of
+    /// another tag merges the two. Every bookmark that carried the tag gets a
+    /// new version, the changes are announced as coming from `origin`. Returns
+    /// the resulting tag, `None` when the user has no tag with the id.
+    pub async fn rename(
+        &self,
+        user_id: &str,
+        origin: Option<&str>,
+        id: &str,
+        name: &str,
+    ) -> anyhow::Result<Option<Tag>> {
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .context("error beginning transaction")?;
+
+        // serializes with the user's syncs, which change assignments
+        lock_user(&mut tx, user_id).await?;
+
+        let Some(source) = query_scalar!(
+            r#"SELECT name FROM tags WHERE id = $1 AND user_id = $2"#,
+            id,
+            user_id,
+        )
+        .fetch_optional(&mut *tx)
+        .await
+        .context("error getting tag")?
+        else {
+            return Ok(None);
+        };
+
+        let mut result_id = id.to_owned();
+
+        if source != name {
+            let bookmark_ids = query_scalar!(
+                r#"SELECT bookmark_id FROM bookmark_tags WHERE tag_id = $1 ORDER BY bookmark_id"#,
+                id,
+            )
+            .fetch_all(&mut *tx)
+            .await
+            .context("error getting tagged bookmarks")?;
+
+            let matched_before = get_matches(&mut tx, user_id, &bookmark_ids)
+                .await
+                .context("error matching saved searches")?;
+
+            let target = query_scalar!(
+                r#"SELECT id FROM tags WHERE user_id = $1 AND name = $2"#,
+                user_id,
+                name,
+            )
+            .fetch_optional(&mut *tx)
+            .await
+            .context("error getting target tag")?;
+
+            if let Some(target) = target {
+                query!(
+                    r#"
+                    INSERT INTO bookmark_tags (bookmark_id, tag_id)
+                    SELECT bookmark_id, $2 FROM bookmark_tags WHERE tag_id = $1
+                    ON CONFLICT DO NOTHING
+                    "#,
+                    id,
+                    target,
+                )
+                .execute(&mut *tx)
+                .await
+                .context("error merging tag assignments")?;
+
+                query!(r#"DELETE FROM tags WHERE id = $1"#, id)
+                    .execute(&mut *tx)
+                    .await
+                    .context("error deleting merged tag")?;
+
+                result_id = target;
+            } else {
+                query!(r#"UPDATE tags SET name = $2 WHERE id = $1"#, id, name)
+                    .execute(&mut *tx)
+                    .await
+                    .context("error renaming tag")?;
+            }
+
+            bump_versions(&mut tx, user_id, origin, &bookmark_ids)
+                .await
+                .context("error bumping bookmark versions")?;
+
+            announce_new_matches(&mut tx, user_id, &matched_before, &bookmark_ids)
+                .await
+                .context("error announcing saved search matches")?;
+        }
+
+        let count = query_scalar!(
+            r#"
+            SELECT COUNT(*) as "count!"
+            FROM bookmark_tags bt
+            JOIN bookmarks b ON b.id = bt.bookmark_id
+            WHERE bt.tag_id = $1
+            AND b.deleted_at IS NULL
+            "#,
+            result_id,
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .context("error counting tagged bookmarks")?;
+
+        tx.commit().await.context("error committing transaction")?;
+
+        Ok(Some(Tag {
+            id: result_id,
+            name: name.to_owned(),
+            count,
+        }))
+    }
+
+    /// Deletes tags that aren't on any bookmark anymore. Returns the number of
+    /// deleted tags.
+    pub async fn purge_unused(&self) -> anyhow::Result<u64> {
+        let user_ids = query_scalar!(
+            r#"
+            SELECT DISTINCT user_id
+            FROM tags t
+            WHERE NOT EXISTS (SELECT 1 FROM bookmark_tags bt WHERE bt.tag_id = t.id)
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut purged = 0;
+
+        for user_id in user_ids {
+            let mut tx = self
+                .pool
+                .begin()
+                .await
+                .context("error beginning transaction")?;
+
+            // writes create tags and then assign them under the user lock, so
+            // with it no tag is about to be assigned. the delete has to be a
+            // statement of its own to see what was assigned while we waited
+            lock_user(&mut tx, &user_id).await?;
+
+            let result = query!(
+                r#"
+                DELETE FROM tags t
+                WHERE t.user_id = $1
+                AND NOT EXISTS (SELECT 1 FROM bookmark_tags bt WHERE bt.tag_id = t.id)
+                "#,
+                user_id,
+            )
+            .execute(&mut *tx)
+            .await?;
+
+            tx.commit().await.context("error committing transaction")?;
+
+            purged += result.rows_affected();
+        }
+
+        Ok(purged)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Tag {
+    pub id: String,
+    pub name: String,
+    /// Number of bookmarks with the tag that aren't deleted.
+    pub count: i64,
+}
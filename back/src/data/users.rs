@@ -1,6 +1,6 @@
 use anyhow::Context;
 use serde::{Deserialize, Serialize};
-use sqlx::{query, query_as, PgPool};
+use sqlx::{query, query_as, PgConnection, PgPool};
 
 use super::Session;
 
@@ -11,23 +11,27 @@ pub struct Users {
 
 impl Users {
     pub async fn get(&self, id: &str) -> anyhow::Result<Option<User>> {
-        let row = query_as!(User, r#"select * from users where id = $1;"#, id)
-            .fetch_optional(&self.pool)
-            .await?;
+        let row = query_as!(
+            User,
+            r#"select id, username, password_hash from users where id = $1;"#,
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
 
-        return Ok(row);
+        Ok(row)
     }
 
     pub async fn get_by_username(&self, username: &str) -> anyhow::Result<Option<User>> {
         let row = query_as!(
             User,
-            r#"select * from users where username = $1;"#,
+            r#"select id, username, password_hash from users where username = $1;"#,
             username
         )
         .fetch_optional(&self.pool)
         .await?;
 
-        return Ok(row);
+        Ok(row)
     }
 
     pub async fn insert(&self, user: &User) -> anyhow::Result<()> {
@@ -43,6 +47,24 @@ impl Users {
         Ok(())
     }
 
+    /// Returns `false` without changing anything when another user has the
+    /// username.
+    pub async fn update_username(&self, id: &str, username: &str) -> anyhow::Result<bool> {
+        let result = query!(
+            r#"update users set username = $2 where id = $1;"#,
+            id,
+            username
+        )
+        .execute(&self.pool)
+        .await;
+
+        match result {
+            Ok(_) => Ok(true),
+            Err(sqlx::Error::Database(err)) if err.is_unique_violation() => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
     pub async fn insert_with_session(&self, user: &User, session: &Session) -> anyhow::Result<()> {
         let mut tx = self
             .pool
@@ -78,10 +100,21 @@ impl Users {
 
         tx.commit().await.context("error committing transaction")?;
 
-        return Ok(());
+        Ok(())
     }
 }
 
+/// Serializes writes that span several of the user's rows with the user's
+/// other writes, until the transaction ends.
+pub(super) async fn lock_user(conn: &mut PgConnection, user_id: &str) -> anyhow::Result<()> {
+    query!(r#"select id from users where id = $1 for update;"#, user_id)
+        .fetch_one(&mut *conn)
+        .await
+        .context("error locking user")?;
+
+    Ok(())
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct User {
     pub id: String,
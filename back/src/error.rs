@@ -3,9 +3,11 @@ use axum::{
     Json,
 };
 use hyper::StatusCode;
-use serde_json::json;
+use serde_json::{json, Value};
 use tracing::error;
 
+use crate::validation::FieldError;
+
 #[derive(Debug, thiserror::Error)]
 pub enum ApiError {
     #[error(transparent)]
@@ -14,18 +16,33 @@ pub enum ApiError {
     #[error("{0}")]
     BadRequest(String),
 
+    /// A bad request with what's wrong with which fields.
+    #[error("invalid fields")]
+    InvalidFields(Vec<FieldError>),
+
     #[error("{0}")]
     Unauthorized(String),
 
+    #[error("{0}")]
+    Forbidden(String),
+
     #[error("{0}")]
     NotFound(String),
 
-    #[error("forbidden")]
-    Forbidden,
+    #[error("{0}")]
+    Conflict(String),
+
+    #[error("{0}")]
+    Gone(String),
+
+    #[error("{0}")]
+    UnprocessableEntity(String),
 }
 
-impl IntoResponse for ApiError {
-    fn into_response(self) -> Response {
+impl ApiError {
+    /// The status and JSON body the error is sent as, unexpected errors are
+    /// logged and their details left out.
+    pub fn into_status_and_body(self) -> (StatusCode, Value) {
         let (status_code, error_message) = match self {
             ApiError::UnexpectedError(err) => {
                 error!("Unexpected error: {:#?}", err);
@@ -33,11 +50,28 @@ impl IntoResponse for ApiError {
                 (StatusCode::INTERNAL_SERVER_ERROR, "unexpected error".into())
             }
             ApiError::BadRequest(err) => (StatusCode::BAD_REQUEST, err),
-            ApiError::Unauthorized(err) => (StatusCode::UNAUTHORIZED, err.into()),
-            ApiError::NotFound(err) => (StatusCode::NOT_FOUND, err.into()),
-            ApiError::Forbidden => (StatusCode::FORBIDDEN, "forbidden".into()),
+            ApiError::InvalidFields(fields) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    json!({ "error": "invalid fields", "fields": fields }),
+                );
+            }
+            ApiError::Unauthorized(err) => (StatusCode::UNAUTHORIZED, err),
+            ApiError::Forbidden(err) => (StatusCode::FORBIDDEN, err),
+            ApiError::NotFound(err) => (StatusCode::NOT_FOUND, err),
+            ApiError::Conflict(err) => (StatusCode::CONFLICT, err),
+            ApiError::Gone(err) => (StatusCode::GONE, err),
+            ApiError::UnprocessableEntity(err) => (StatusCode::UNPROCESSABLE_ENTITY, err),
         };
 
-        return (status_code, Json(json!({ "error": error_message }))).into_response();
+        (status_code, json!({ "error": error_message }))
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status_code, body) = self.into_status_and_body();
+
+        (status_code, Json(body)).into_response()
     }
 }
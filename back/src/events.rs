@@ -0,0 +1,531 @@
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context as TaskContext, Poll},
+};
+
+use anyhow::Context;
+use axum::response::sse::Event;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgListener;
+use tokio::sync::{broadcast, Notify};
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+    Stream, StreamExt,
+};
+use tracing::{error, warn};
+
+use crate::{
+    config::CONFIG,
+    data::{
+        Bookmark, Change, ChangeItem, ChangeNotification, ChangeOp, Collection, Data,
+        MatchNotification, SavedSearch, CHANGES_CHANNEL, EVENTS_CHANNEL, MATCHES_CHANNEL,
+    },
+};
+
+const CHANNEL_CAPACITY: usize = 100;
+
+// subscribers that missed more than this have to go through /bootstrap instead
+const REPLAY_LIMIT: i64 = 1000;
+
+/// Bumped on breaking changes to the shape of event payloads.
+const ENVELOPE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Message {
+    pub user_id: String,
+    /// Subscribers with the same origin skip the message, they caused it.
+    #[serde(default)]
+    pub origin: Option<String>,
+    pub event: ServerEvent,
+}
+
+/// Identifies one client of a session, e.g. a browser tab. Scoped to the
+/// session so clients can't pick an id that silences another session.
+pub fn origin(session_id: &str, client_id: &str) -> String {
+    format!("{session_id}.{client_id}")
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", content = "data")]
+pub enum ServerEvent {
+    #[serde(rename = "bookmark.upserted")]
+    BookmarkUpserted(Bookmark),
+    #[serde(rename = "bookmark.deleted")]
+    BookmarkDeleted(Bookmark),
+    #[serde(rename = "collection.upserted")]
+    CollectionUpserted(Collection),
+    #[serde(rename = "collection.deleted")]
+    CollectionDeleted(Collection),
+    #[serde(rename = "saved_search.upserted")]
+    SavedSearchUpserted(SavedSearch),
+    #[serde(rename = "saved_search.deleted")]
+    SavedSearchDeleted(SavedSearch),
+    /// A bookmark that didn't match the saved search before a write does now.
+    #[serde(rename = "saved_search.matched")]
+    SavedSearchMatched {
+        saved_search_id: String,
+        bookmark: Bookmark,
+    },
+    #[serde(rename = "session.revoked")]
+    SessionRevoked { session_id: String },
+    #[serde(rename = "resync.required")]
+    ResyncRequired { reason: ResyncReason },
+    #[serde(rename = "account.updated")]
+    AccountUpdated { id: String, username: String },
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ResyncReason {
+    /// The subscriber fell behind and events were dropped.
+    Lagged,
+    /// The missed changes can't be replayed from the change log.
+    ReplayUnavailable,
+}
+
+#[derive(Serialize)]
+pub struct Envelope<'a> {
+    v: u32,
+    #[serde(flatten)]
+    event: &'a ServerEvent,
+}
+
+impl ServerEvent {
+    pub fn from_change(change: Change) -> Self {
+        match (change.op, change.item) {
+            (ChangeOp::Upsert, ChangeItem::Bookmark(bookmark)) => {
+                ServerEvent::BookmarkUpserted(bookmark)
+            }
+            (ChangeOp::Delete, ChangeItem::Bookmark(bookmark)) => {
+                ServerEvent::BookmarkDeleted(bookmark)
+            }
+            (ChangeOp::Upsert, ChangeItem::Collection(collection)) => {
+                ServerEvent::CollectionUpserted(collection)
+            }
+            (ChangeOp::Delete, ChangeItem::Collection(collection)) => {
+                ServerEvent::CollectionDeleted(collection)
+            }
+            (ChangeOp::Upsert, ChangeItem::SavedSearch(saved_search)) => {
+                ServerEvent::SavedSearchUpserted(saved_search)
+            }
+            (ChangeOp::Delete, ChangeItem::SavedSearch(saved_search)) => {
+                ServerEvent::SavedSearchDeleted(saved_search)
+            }
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ServerEvent::BookmarkUpserted(_) => "bookmark.upserted",
+            ServerEvent::BookmarkDeleted(_) => "bookmark.deleted",
+            ServerEvent::CollectionUpserted(_) => "collection.upserted",
+            ServerEvent::CollectionDeleted(_) => "collection.deleted",
+            ServerEvent::SavedSearchUpserted(_) => "saved_search.upserted",
+            ServerEvent::SavedSearchDeleted(_) => "saved_search.deleted",
+            ServerEvent::SavedSearchMatched { .. } => "saved_search.matched",
+            ServerEvent::SessionRevoked { .. } => "session.revoked",
+            ServerEvent::ResyncRequired { .. } => "resync.required",
+            ServerEvent::AccountUpdated { .. } => "account.updated",
+        }
+    }
+
+    /// Seq of the change behind the event, only bookmark, collection and saved
+    /// search writes have one.
+    pub fn seq(&self) -> Option<i64> {
+        match self {
+            ServerEvent::BookmarkUpserted(bookmark) | ServerEvent::BookmarkDeleted(bookmark) => {
+                Some(bookmark.version)
+            }
+            ServerEvent::CollectionUpserted(collection)
+            | ServerEvent::CollectionDeleted(collection) => Some(collection.version),
+            ServerEvent::SavedSearchUpserted(saved_search)
+            | ServerEvent::SavedSearchDeleted(saved_search) => Some(saved_search.version),
+            _ => None,
+        }
+    }
+
+    pub fn envelope(&self) -> Envelope<'_> {
+        Envelope {
+            v: ENVELOPE_VERSION,
+            event: self,
+        }
+    }
+
+    /// Events with a seq use it as their SSE id, so reconnecting clients can
+    /// resume from it with `Last-Event-ID`.
+    pub fn to_sse(&self) -> anyhow::Result<Event> {
+        let data = serde_json::to_string(&self.envelope())?;
+
+        let event = Event::default().event(self.name()).data(data);
+
+        Ok(match self.seq() {
+            Some(seq) => event.id(seq.to_string()),
+            None => event,
+        })
+    }
+}
+
+/// Fans messages out to the subscribers of each user. Every user gets their
+/// own channel, so a user with a lot of traffic can only make their own
+/// subscribers lag.
+#[derive(Clone, Default)]
+pub struct Hub {
+    channels: Arc<Mutex<HashMap<String, broadcast::Sender<Message>>>>,
+}
+
+impl Hub {
+    pub fn subscribe(&self, user_id: &str) -> broadcast::Receiver<Message> {
+        let mut channels = self.channels.lock().expect("hub lock poisoned");
+
+        channels
+            .entry(user_id.to_owned())
+            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+            .subscribe()
+    }
+
+    /// Drops the user's channel once their last subscriber is gone.
+    fn unsubscribe(&self, user_id: &str) {
+        let mut channels = self.channels.lock().expect("hub lock poisoned");
+
+        if channels
+            .get(user_id)
+            .is_some_and(|tx| tx.receiver_count() == 0)
+        {
+            channels.remove(user_id);
+        }
+    }
+
+    pub fn has_subscribers(&self, user_id: &str) -> bool {
+        let channels = self.channels.lock().expect("hub lock poisoned");
+
+        channels
+            .get(user_id)
+            .is_some_and(|tx| tx.receiver_count() > 0)
+    }
+
+    /// Ends every subscription, subscribers reconnect and catch up from the
+    /// change log.
+    pub fn close_all(&self) {
+        let mut channels = self.channels.lock().expect("hub lock poisoned");
+
+        channels.clear();
+    }
+
+    pub fn send(&self, message: Message) {
+        let mut channels = self.channels.lock().expect("hub lock poisoned");
+
+        if let Some(tx) = channels.get(&message.user_id) {
+            // only fails when every subscriber is gone
+            if tx.send(message.clone()).is_err() {
+                channels.remove(&message.user_id);
+            }
+        }
+    }
+}
+
+/// Streams the user's events to one of their sessions, leaving out live
+/// events caused by `origin`. With `last_seq` the changes after it are replayed
+/// from the change log first. The stream ends right after telling the session
+/// it has been revoked.
+pub async fn subscribe(
+    hub: &Hub,
+    data: &Data,
+    user_id: &str,
+    session_id: &str,
+    origin: Option<String>,
+    last_seq: Option<i64>,
+) -> anyhow::Result<impl Stream<Item = ServerEvent>> {
+    // subscribe before reading the replay so nothing committed in between is
+    // lost, duplicates are skipped by their seq below
+    let rx = hub.subscribe(user_id);
+
+    let mut replay = vec![];
+    let mut replayed_seq = 0;
+
+    if let Some(last_seq) = last_seq {
+        replayed_seq = last_seq;
+
+        let purged_seq = data
+            .changes
+            .get_purged_seq(user_id)
+            .await
+            .context("error getting purged seq")?;
+
+        let changes = data
+            .changes
+            .get_since(user_id, last_seq, REPLAY_LIMIT + 1)
+            .await
+            .context("error getting changes")?;
+
+        if last_seq < purged_seq || changes.len() as i64 > REPLAY_LIMIT {
+            replay.push(ServerEvent::ResyncRequired {
+                reason: ResyncReason::ReplayUnavailable,
+            });
+        } else {
+            for change in changes {
+                replayed_seq = change.seq;
+                replay.push(ServerEvent::from_change(change));
+            }
+        }
+    }
+
+    let session_id = session_id.to_owned();
+    let revoked = Arc::new(Notify::new());
+    let revoke = revoked.clone();
+
+    let live = BroadcastStream::new(rx).filter_map(move |res| match res {
+        Ok(message) => {
+            if message.event.seq().is_some_and(|seq| seq <= replayed_seq) {
+                return None;
+            }
+
+            if origin.is_some() && message.origin == origin {
+                return None;
+            }
+
+            if let ServerEvent::SessionRevoked { session_id: id } = &message.event {
+                if *id == session_id {
+                    revoke.notify_one();
+                }
+            }
+
+            Some(message.event)
+        }
+        // the receiver fell behind and messages were dropped, the client has
+        // to catch up through /bootstrap
+        Err(BroadcastStreamRecvError::Lagged(_)) => Some(ServerEvent::ResyncRequired {
+            reason: ResyncReason::Lagged,
+        }),
+    });
+
+    let live = Subscription {
+        stream: live,
+        _unsubscribe: Unsubscribe {
+            hub: hub.clone(),
+            user_id: user_id.to_owned(),
+        },
+    };
+
+    let stream = tokio_stream::iter(replay).chain(live);
+
+    Ok(futures::StreamExt::take_until(stream, async move {
+        revoked.notified().await
+    }))
+}
+
+/// A stream of a hub channel's receiver, fields drop in order so the receiver
+/// is gone by the time the channel is checked for subscribers.
+struct Subscription<S> {
+    stream: S,
+    _unsubscribe: Unsubscribe,
+}
+
+impl<S: Stream + Unpin> Stream for Subscription<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<S::Item>> {
+        Pin::new(&mut self.stream).poll_next(cx)
+    }
+}
+
+struct Unsubscribe {
+    hub: Hub,
+    user_id: String,
+}
+
+impl Drop for Unsubscribe {
+    fn drop(&mut self) {
+        self.hub.unsubscribe(&self.user_id);
+    }
+}
+
+/// Publishes an event that isn't backed by the change log to the user's
+/// subscribers on every instance.
+pub async fn publish(data: &Data, message: &Message) -> anyhow::Result<()> {
+    let payload = serde_json::to_string(message)?;
+
+    data.notifications.notify(EVENTS_CHANNEL, &payload).await
+}
+
+/// Forwards events published by any instance to this instance's hub. Changes
+/// and saved search matches are read back from the database, their
+/// notifications only carry seq ranges and ids.
+pub async fn listen(data: Data, hub: Hub) {
+    loop {
+        if let Err(err) = forward_notifications(&data, &hub).await {
+            error!("error listening for changes: {:#?}", err);
+        }
+
+        // anything announced while we weren't listening is lost
+        hub.close_all();
+
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    }
+}
+
+async fn forward_notifications(data: &Data, hub: &Hub) -> anyhow::Result<()> {
+    let mut listener = PgListener::connect(&CONFIG.database_url)
+        .await
+        .context("error connecting listener")?;
+
+    listener
+        .listen_all([CHANGES_CHANNEL, EVENTS_CHANNEL, MATCHES_CHANNEL])
+        .await
+        .context("error listening")?;
+
+    loop {
+        let Some(notification) = listener
+            .try_recv()
+            .await
+            .context("error receiving notification")?
+        else {
+            warn!("listener connection lost, reconnecting");
+            hub.close_all();
+            continue;
+        };
+
+        if notification.channel() == EVENTS_CHANNEL {
+            match serde_json::from_str::<Message>(notification.payload()) {
+                Ok(message) => hub.send(message),
+                Err(err) => error!("invalid event notification: {:#?}", err),
+            }
+
+            continue;
+        }
+
+        if notification.channel() == MATCHES_CHANNEL {
+            match serde_json::from_str::<MatchNotification>(notification.payload()) {
+                Ok(notification) => forward_match(data, hub, notification).await?,
+                Err(err) => error!("invalid match notification: {:#?}", err),
+            }
+
+            continue;
+        }
+
+        let notification = match serde_json::from_str::<ChangeNotification>(notification.payload())
+        {
+            Ok(notification) => notification,
+            Err(err) => {
+                error!("invalid change notification: {:#?}", err);
+                continue;
+            }
+        };
+
+        if !hub.has_subscribers(&notification.user_id) {
+            continue;
+        }
+
+        let changes = data
+            .changes
+            .get_range(
+                &notification.user_id,
+                notification.after_seq,
+                notification.last_seq,
+                notification.last_seq - notification.after_seq,
+            )
+            .await
+            .context("error getting changes")?;
+
+        for change in changes {
+            hub.send(Message {
+                user_id: notification.user_id.to_owned(),
+                origin: notification.origin.to_owned(),
+                event: ServerEvent::from_change(change),
+            });
+        }
+    }
+}
+
+async fn forward_match(
+    data: &Data,
+    hub: &Hub,
+    notification: MatchNotification,
+) -> anyhow::Result<()> {
+    if !hub.has_subscribers(&notification.user_id) {
+        return Ok(());
+    }
+
+    // bookmarks purged since are left out
+    let bookmarks = data
+        .bookmarks
+        .get_many(&notification.user_id, &notification.bookmark_ids)
+        .await
+        .context("error getting matched bookmarks")?;
+
+    for bookmark in bookmarks {
+        hub.send(Message {
+            user_id: notification.user_id.to_owned(),
+            origin: None,
+            event: ServerEvent::SavedSearchMatched {
+                saved_search_id: notification.saved_search_id.to_owned(),
+                bookmark,
+            },
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(user_id: &str) -> Message {
+        Message {
+            user_id: user_id.to_owned(),
+            origin: None,
+            event: ServerEvent::SessionRevoked {
+                session_id: "session".to_owned(),
+            },
+        }
+    }
+
+    fn channel_count(hub: &Hub) -> usize {
+        hub.channels.lock().unwrap().len()
+    }
+
+    #[test]
+    fn unsubscribe_drops_channels_without_subscribers() {
+        let hub = Hub::default();
+
+        let first = hub.subscribe("user");
+        let second = hub.subscribe("user");
+
+        drop(first);
+        hub.unsubscribe("user");
+        assert_eq!(channel_count(&hub), 1);
+
+        drop(second);
+        hub.unsubscribe("user");
+        assert_eq!(channel_count(&hub), 0);
+    }
+
+    #[test]
+    fn send_drops_channels_without_subscribers() {
+        let hub = Hub::default();
+
+        drop(hub.subscribe("user"));
+        hub.send(message("user"));
+
+        assert_eq!(channel_count(&hub), 0);
+    }
+
+    #[test]
+    fn dropping_a_subscription_unsubscribes() {
+        let hub = Hub::default();
+
+        let subscription = Subscription {
+            stream: BroadcastStream::new(hub.subscribe("user")),
+            _unsubscribe: Unsubscribe {
+                hub: hub.clone(),
+                user_id: "user".to_owned(),
+            },
+        };
+        assert_eq!(channel_count(&hub), 1);
+
+        drop(subscription);
+        assert_eq!(channel_count(&hub), 0);
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    data::{Bookmark, Collection},
+    markdown::{self, escape},
+    url_policy,
+};
+
+enum Item<'a> {
+    Collection(&'a Collection),
+    Bookmark(&'a Bookmark),
+}
+
+impl Item<'_> {
+    fn position(&self) -> (i64, &str) {
+        match self {
+            Item::Collection(c) => (c.position, &c.id),
+            Item::Bookmark(b) => (b.position.unwrap_or_default(), &b.id),
+        }
+    }
+}
+
+/// Writes the bookmarks as a Netscape bookmark file, the format browsers
+/// import and export. Collections become folders, notes are rendered to HTML.
+pub fn netscape(collections: &[Collection], bookmarks: &[Bookmark]) -> String {
+    let ids = collections.iter().map(|c| &c.id).collect::<HashSet<_>>();
+
+    let mut children = HashMap::<Option<&str>, Vec<Item>>::new();
+
+    for collection in collections {
+        let parent_id = collection
+            .parent_id
+            .as_ref()
+            .filter(|id| ids.contains(id))
+            .map(String::as_str);
+
+        children
+            .entry(parent_id)
+            .or_default()
+            .push(Item::Collection(collection));
+    }
+
+    // bookmarks saved before the url policy existed may still have urls that
+    // aren't safe to link to
+    for bookmark in bookmarks
+        .iter()
+        .filter(|b| url_policy::normalize(&b.url).is_ok())
+    {
+        let collection_id = bookmark
+            .collection_id
+            .as_ref()
+            .and_then(Option::as_ref)
+            .filter(|id| ids.contains(id))
+            .map(String::as_str);
+
+        children
+            .entry(collection_id)
+            .or_default()
+            .push(Item::Bookmark(bookmark));
+    }
+
+    for items in children.values_mut() {
+        items.sort_by(|a, b| a.position().cmp(&b.position()));
+    }
+
+    let mut out = String::from(
+        "<!DOCTYPE NETSCAPE-Bookmark-file-1>\n\
+         <META HTTP-EQUIV=\"Content-Type\" CONTENT=\"text/html; charset=UTF-8\">\n\
+         <TITLE>Bookmarks</TITLE>\n\
+         <H1>Bookmarks</H1>\n",
+    );
+
+    write_list(&mut out, &children, None, 0);
+
+    out
+}
+
+fn write_list(
+    out: &mut String,
+    children: &HashMap<Option<&str>, Vec<Item>>,
+    parent_id: Option<&str>,
+    depth: usize,
+) {
+    let indent = "    ".repeat(depth);
+
+    out.push_str(&format!("{indent}<DL><p>\n"));
+
+    for item in children.get(&parent_id).into_iter().flatten() {
+        match item {
+            Item::Collection(c) => {
+                out.push_str(&format!(
+                    "{indent}    <DT><H3 LAST_MODIFIED=\"{}\">{}</H3>\n",
+                    c.updated_at.timestamp(),
+                    escape(&c.name),
+                ));
+
+                write_list(out, children, Some(&c.id), depth + 1);
+            }
+            Item::Bookmark(b) => {
+                out.push_str(&format!(
+                    "{indent}    <DT><A HREF=\"{}\" LAST_MODIFIED=\"{}\" TAGS=\"{}\">{}</A>\n",
+                    escape(&b.url),
+                    b.updated_at.timestamp(),
+                    escape(&b.tags.as_deref().unwrap_or_default().join(",")),
+                    escape(&b.title),
+                ));
+
+                let notes = b.notes.as_deref().unwrap_or_default();
+
+                if !notes.trim().is_empty() {
+                    out.push_str(&format!(
+                        "{indent}    <DD>{}\n",
+                        markdown::render(notes).trim_end()
+                    ));
+                }
+            }
+        }
+    }
+
+    out.push_str(&format!("{indent}</DL><p>\n"));
+}
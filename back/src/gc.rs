@@ -0,0 +1,79 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use chrono::Utc;
+use tracing::{debug, error};
+
+use crate::{config::CONFIG, data::Data};
+
+const INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+pub async fn run(data: Data) {
+    let mut interval = tokio::time::interval(INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(err) = collect(&data).await {
+            error!("error collecting garbage: {:#?}", err);
+        }
+    }
+}
+
+async fn collect(data: &Data) -> anyhow::Result<()> {
+    let backfilled = data
+        .bookmarks
+        .backfill_canonical_urls()
+        .await
+        .context("error backfilling canonical urls")?;
+
+    let tombstoned = data
+        .bookmarks
+        .tombstone_unsafe_urls()
+        .await
+        .context("error deleting bookmarks with unsafe urls")?;
+
+    let before = Utc::now() - chrono::Duration::days(CONFIG.tombstone_retention_days);
+
+    let purged = data
+        .bookmarks
+        .purge_tombstones(&before)
+        .await
+        .context("error purging tombstones")?;
+
+    let purged_collections = data
+        .collections
+        .purge_tombstones(&before)
+        .await
+        .context("error purging collection tombstones")?;
+
+    let purged_saved_searches = data
+        .saved_searches
+        .purge_tombstones(&before)
+        .await
+        .context("error purging saved search tombstones")?;
+
+    let compacted = data
+        .changes
+        .compact(&before)
+        .await
+        .context("error compacting changes")?;
+
+    let tags = data
+        .tags
+        .purge_unused()
+        .await
+        .context("error purging unused tags")?;
+
+    let idempotency_keys = data
+        .idempotency_keys
+        .purge(&(Utc::now() - chrono::Duration::hours(CONFIG.idempotency_window_hours)))
+        .await
+        .context("error purging idempotency keys")?;
+
+    debug!(
+        "backfilled {backfilled} canonical urls, deleted {tombstoned} bookmarks with unsafe urls, purged {purged} bookmark, {purged_collections} collection and {purged_saved_searches} saved search tombstones, {tags} tags, {idempotency_keys} idempotency keys, compacted {compacted} changes"
+    );
+
+    Ok(())
+}
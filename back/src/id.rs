@@ -1,5 +1,5 @@
 use ulid::Ulid;
 
 pub fn new_id() -> String {
-    return Ulid::new().to_string();
+    Ulid::new().to_string()
 }
@@ -1,4 +1,4 @@
-use std::{convert::Infallible, sync::Arc, time::Duration};
+use std::{convert::Infallible, time::Duration};
 
 use anyhow::Context;
 use auth::{
@@ -6,25 +6,30 @@ use auth::{
     password_verify, Auth, UserId,
 };
 use axum::{
-    extract::{Json, Query, State},
-    http::HeaderValue,
+    extract::{DefaultBodyLimit, Json, Path, Query, State, WebSocketUpgrade},
+    http::{HeaderMap, HeaderName, HeaderValue},
     response::{
         sse::{Event, KeepAlive},
         AppendHeaders, IntoResponse, Sse,
     },
-    routing::{get, post},
+    routing::{delete, get, patch, post},
     Extension, Router,
 };
 use chrono::{DateTime, Utc};
 use config::CONFIG;
-use data::{Bookmark, Data, Session, User};
+use data::{
+    Bookmark, Change, Collection, Data, DeleteMode, DuplicateGroup, SavedSearch, Session, Tag,
+    User, Visit,
+};
 use error::ApiError;
+use events::{Hub, Message, ServerEvent};
 use hyper::{header, Method};
 use id::new_id;
+use search::{ListParams, SearchParams, SearchResponse};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use tokio::sync::broadcast;
-use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
+use sync::{SyncRequest, SyncResponse};
+use tokio_stream::{Stream, StreamExt};
 use tower_http::cors::CorsLayer;
 use tracing::{debug, level_filters::LevelFilter};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
@@ -33,7 +38,20 @@ mod auth;
 mod config;
 mod data;
 mod error;
+mod events;
+mod export;
+mod gc;
 mod id;
+mod markdown;
+mod query;
+mod saved_searches;
+mod search;
+mod sync;
+#[cfg(test)]
+mod testing;
+mod url_policy;
+mod validation;
+mod ws;
 
 #[tokio::main]
 async fn main() {
@@ -44,14 +62,35 @@ async fn main() {
 
     let data = Data::new(&CONFIG.database_url).await.expect("data init");
 
-    let (tx, _) = broadcast::channel::<Message>(100);
-    let tx = Arc::new(tx);
+    tokio::spawn(gc::run(data.clone()));
+
+    let hub = Hub::default();
+
+    tokio::spawn(events::listen(data.clone(), hub.clone()));
 
     let routes = Router::new()
         .route("/events", get(sse_handler))
-        .route("/sync", post(sync_handler))
+        .route("/ws", get(ws_handler))
+        .route(
+            "/sync",
+            post(sync_handler).layer(DefaultBodyLimit::max(SYNC_BODY_LIMIT)),
+        )
         .route("/bootstrap", get(bootstrap_handler))
-        .route("/me", get(me_handler))
+        .route("/bookmarks", get(bookmarks_handler))
+        .route("/collections/{id}", delete(delete_collection_handler))
+        .route("/duplicates", get(duplicates_handler))
+        .route("/duplicates/merge", post(merge_duplicates_handler))
+        .route("/export", get(export_handler))
+        .route("/saved-searches", get(saved_searches_handler))
+        .route(
+            "/saved-searches/{id}/bookmarks",
+            get(saved_search_bookmarks_handler),
+        )
+        .route("/search", get(search_handler))
+        .route("/tags", get(tags_handler))
+        .route("/tags/{id}", patch(rename_tag_handler))
+        .route("/visits", post(visits_handler))
+        .route("/me", get(me_handler).patch(update_me_handler))
         .route("/auth/login", post(login_handler))
         .route("/auth/register", post(register_handler))
         .route("/auth/logout", post(logout_handler));
@@ -59,7 +98,7 @@ async fn main() {
     let api = Router::new()
         .nest("/api", routes)
         .layer(cors())
-        .layer(Extension(tx))
+        .layer(Extension(hub))
         .with_state(data);
 
     let listener = tokio::net::TcpListener::bind("0.0.0.0:8000").await.unwrap();
@@ -85,6 +124,9 @@ fn cors() -> CorsLayer {
             header::ACCEPT_ENCODING,
             header::ACCEPT_LANGUAGE,
             header::COOKIE,
+            IDEMPOTENCY_KEY.clone(),
+            CLIENT_ID.clone(),
+            LAST_EVENT_ID.clone(),
         ])
         .allow_origin(
             CONFIG
@@ -94,53 +136,103 @@ fn cors() -> CorsLayer {
         )
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-struct Message {
-    user_id: String,
-    bookmark: Bookmark,
-}
+// large enough for a first sync of a big library
+const SYNC_BODY_LIMIT: usize = 64 * 1024 * 1024;
 
-type Tx = broadcast::Sender<Message>;
+static IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
 
-async fn sse_handler(
-    Extension(tx): Extension<Arc<Tx>>,
-    UserId(user_id): UserId,
-) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
-    let rx = tx.subscribe();
-    let stream = BroadcastStream::new(rx).filter_map(move |res| {
-        if let Ok(message) = res {
-            if message.user_id != user_id {
-                return None;
-            }
+static CLIENT_ID: HeaderName = HeaderName::from_static("x-client-id");
 
-            match serde_json::to_string(&message.bookmark) {
-                Ok(data) => Some(Ok(Event::default().data(data))),
-                Err(_) => None,
-            }
-        } else {
-            None
-        }
-    });
+static LAST_EVENT_ID: HeaderName = HeaderName::from_static("last-event-id");
 
-    Sse::new(stream).keep_alive(KeepAlive::new().interval(Duration::from_secs(15)))
+#[derive(Debug, Serialize, Deserialize)]
+struct SseParams {
+    /// Events caused by syncs with the same `X-Client-Id` are skipped.
+    client_id: Option<String>,
+}
+
+async fn sse_handler(
+    Extension(hub): Extension<Hub>,
+    data: State<Data>,
+    Auth(auth): Auth,
+    headers: HeaderMap,
+    Query(params): Query<SseParams>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    let last_event_id = headers
+        .get(&LAST_EVENT_ID)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<i64>().ok());
+
+    let origin = params
+        .client_id
+        .map(|client_id| events::origin(&auth.session_id, &client_id));
+
+    let stream = events::subscribe(
+        &hub,
+        &data,
+        &auth.user_id,
+        &auth.session_id,
+        origin,
+        last_event_id,
+    )
+    .await?
+    .filter_map(|event| event.to_sse().ok().map(Ok));
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::new().interval(Duration::from_secs(15))))
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct SyncRequest {
-    bookmarks: Vec<Bookmark>,
+struct WsParams {
+    /// Same as the `Last-Event-ID` header of `/events`.
+    last_event_id: Option<i64>,
 }
 
+async fn ws_handler(
+    Extension(hub): Extension<Hub>,
+    data: State<Data>,
+    Auth(auth): Auth,
+    Query(params): Query<WsParams>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> Result<impl IntoResponse, ApiError> {
+    // cors doesn't cover websockets, without this any site could open a socket
+    // with the user's cookie. browsers always send an origin, other clients
+    // can't be made to send the cookie by another site
+    if let Some(origin) = headers.get(header::ORIGIN) {
+        if origin.as_bytes() != CONFIG.front_url.trim_end_matches('/').as_bytes() {
+            return Err(ApiError::Forbidden("origin not allowed".to_owned()));
+        }
+    }
+
+    // the socket is its own client, its syncs are never echoed back to it
+    let origin = events::origin(&auth.session_id, &new_id());
+
+    let events = events::subscribe(
+        &hub,
+        &data,
+        &auth.user_id,
+        &auth.session_id,
+        Some(origin.to_owned()),
+        params.last_event_id,
+    )
+    .await?;
+
+    Ok(ws.on_upgrade(move |socket| ws::serve(socket, data.0, auth.user_id, origin, events)))
+}
+
+const BOOTSTRAP_DEFAULT_LIMIT: i64 = 100;
+const BOOTSTRAP_MAX_LIMIT: i64 = 1000;
+
 #[derive(Debug, Serialize, Deserialize)]
 struct BootstrapRequest {
-    cursor: Option<String>,
+    cursor: Option<i64>,
     limit: Option<i64>,
-    from: DateTime<Utc>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct BootstrapResponse {
-    bookmarks: Vec<Bookmark>,
-    next_cursor: Option<String>,
+    changes: Vec<Change>,
+    next_cursor: Option<i64>,
 }
 
 async fn bootstrap_handler(
@@ -148,45 +240,307 @@ async fn bootstrap_handler(
     UserId(user_id): UserId,
     Query(req): Query<BootstrapRequest>,
 ) -> Result<Json<BootstrapResponse>, ApiError> {
-    let limit = req.limit.unwrap_or(100);
+    let limit = req
+        .limit
+        .unwrap_or(BOOTSTRAP_DEFAULT_LIMIT)
+        .clamp(1, BOOTSTRAP_MAX_LIMIT);
+    let cursor = req.cursor.unwrap_or(0);
+
+    // a client starting from 0 gets everything that's left anyway, others
+    // might have missed deletes if their cursor is behind the purged changes
+    if cursor > 0 {
+        let purged_seq = data
+            .changes
+            .get_purged_seq(&user_id)
+            .await
+            .context("error getting purged seq")?;
 
-    let bookmarks = data
-        .bookmarks
-        .get_all(&user_id, &req.from, req.cursor.as_deref(), limit)
+        if cursor < purged_seq {
+            return Err(ApiError::Gone("full resync required".to_owned()));
+        }
+    }
+
+    let changes = data
+        .changes
+        .get_since(&user_id, cursor, limit)
         .await
-        .context("error getting bookmarks")?;
+        .context("error getting changes")?;
 
-    let next_cursor = if bookmarks.len() == limit as usize {
-        bookmarks.last().map(|b| b.id.clone())
+    let next_cursor = if changes.len() == limit as usize {
+        changes.last().map(|c| c.seq)
     } else {
         None
     };
 
     Ok(Json(BootstrapResponse {
-        bookmarks,
+        changes,
         next_cursor,
     }))
 }
 
-async fn sync_handler(
-    Extension(tx): Extension<Arc<Tx>>,
+/// The user's bookmarks a page at a time, with `sort=frecency` the most used
+/// ones first.
+async fn bookmarks_handler(
     data: State<Data>,
     UserId(user_id): UserId,
+    Query(params): Query<ListParams>,
+) -> Result<Json<SearchResponse>, ApiError> {
+    let response = search::list(&data, &user_id, params).await?;
+
+    Ok(Json(response))
+}
+
+async fn sync_handler(
+    data: State<Data>,
+    Auth(auth): Auth,
+    headers: HeaderMap,
     Json(req): Json<SyncRequest>,
-) -> Result<(), ApiError> {
-    data.bookmarks
-        .bulk_upsert(&user_id, &req.bookmarks)
+) -> Result<Json<SyncResponse>, ApiError> {
+    let idempotency_key = headers
+        .get(&IDEMPOTENCY_KEY)
+        .map(|value| {
+            value
+                .to_str()
+                .map_err(|_| ApiError::BadRequest("invalid idempotency key".to_owned()))
+        })
+        .transpose()?;
+
+    let origin = client_origin(&headers, &auth.session_id);
+
+    let response = sync::sync(
+        &data,
+        &auth.user_id,
+        origin.as_deref(),
+        idempotency_key,
+        req,
+    )
+    .await?;
+
+    Ok(Json(response))
+}
+
+/// Origin of changes made by a request, from its `X-Client-Id` header.
+fn client_origin(headers: &HeaderMap, session_id: &str) -> Option<String> {
+    headers
+        .get(&CLIENT_ID)
+        .and_then(|value| value.to_str().ok())
+        .map(|client_id| events::origin(session_id, client_id))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DeleteCollectionParams {
+    mode: Option<DeleteMode>,
+}
+
+/// Deletes a collection with everything in it, or with `mode=reparent` moves
+/// its contents to its parent first.
+async fn delete_collection_handler(
+    data: State<Data>,
+    Auth(auth): Auth,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    Query(params): Query<DeleteCollectionParams>,
+) -> Result<Json<Collection>, ApiError> {
+    let origin = client_origin(&headers, &auth.session_id);
+
+    let collection = data
+        .collections
+        .delete(
+            &auth.user_id,
+            origin.as_deref(),
+            &id,
+            params.mode.unwrap_or(DeleteMode::Trash),
+        )
         .await
-        .context("error upserting bookmarks")?;
+        .context("error deleting collection")?
+        .ok_or_else(|| ApiError::NotFound("collection not found".to_owned()))?;
 
-    for bookmark in req.bookmarks {
-        let _ = tx.send(Message {
-            user_id: user_id.to_owned(),
-            bookmark,
-        });
-    }
+    Ok(Json(collection))
+}
+
+async fn duplicates_handler(
+    data: State<Data>,
+    UserId(user_id): UserId,
+) -> Result<Json<Vec<DuplicateGroup>>, ApiError> {
+    let groups = data
+        .bookmarks
+        .get_duplicates(&user_id)
+        .await
+        .context("error getting duplicates")?;
+
+    Ok(Json(groups))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MergeDuplicatesRequest {
+    canonical_url: String,
+}
+
+/// Keeps the oldest bookmark of the group and deletes the rest.
+async fn merge_duplicates_handler(
+    data: State<Data>,
+    Auth(auth): Auth,
+    headers: HeaderMap,
+    Json(req): Json<MergeDuplicatesRequest>,
+) -> Result<Json<Bookmark>, ApiError> {
+    let origin = client_origin(&headers, &auth.session_id);
+
+    let bookmark = data
+        .bookmarks
+        .merge_duplicates(&auth.user_id, origin.as_deref(), &req.canonical_url)
+        .await
+        .context("error merging duplicates")?
+        .ok_or_else(|| ApiError::NotFound("no duplicates to merge".to_owned()))?;
+
+    Ok(Json(bookmark))
+}
+
+async fn export_handler(
+    data: State<Data>,
+    UserId(user_id): UserId,
+) -> Result<impl IntoResponse, ApiError> {
+    let collections = data
+        .collections
+        .get_all(&user_id)
+        .await
+        .context("error getting collections")?;
+
+    let bookmarks = data
+        .bookmarks
+        .get_all(&user_id)
+        .await
+        .context("error getting bookmarks")?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/html; charset=utf-8"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"bookmarks.html\"",
+            ),
+        ],
+        export::netscape(&collections, &bookmarks),
+    ))
+}
+
+async fn saved_searches_handler(
+    data: State<Data>,
+    UserId(user_id): UserId,
+) -> Result<Json<Vec<SavedSearch>>, ApiError> {
+    let saved_searches = data
+        .saved_searches
+        .get_all(&user_id)
+        .await
+        .context("error getting saved searches")?;
+
+    Ok(Json(saved_searches))
+}
+
+/// The bookmarks that currently match the saved search, best matches first.
+async fn saved_search_bookmarks_handler(
+    data: State<Data>,
+    UserId(user_id): UserId,
+    Path(id): Path<String>,
+    Query(params): Query<ListParams>,
+) -> Result<Json<SearchResponse>, ApiError> {
+    let response = saved_searches::evaluate(&data, &user_id, &id, params).await?;
+
+    Ok(Json(response))
+}
+
+async fn search_handler(
+    data: State<Data>,
+    UserId(user_id): UserId,
+    Query(params): Query<SearchParams>,
+) -> Result<Json<SearchResponse>, ApiError> {
+    let response = search::search(&data, &user_id, params).await?;
+
+    Ok(Json(response))
+}
+
+async fn tags_handler(
+    data: State<Data>,
+    UserId(user_id): UserId,
+) -> Result<Json<Vec<Tag>>, ApiError> {
+    let tags = data
+        .tags
+        .list(&user_id)
+        .await
+        .context("error listing tags")?;
+
+    Ok(Json(tags))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RenameTagRequest {
+    name: String,
+}
+
+/// Renaming a tag to the name of another one merges them.
+async fn rename_tag_handler(
+    data: State<Data>,
+    Auth(auth): Auth,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    Json(req): Json<RenameTagRequest>,
+) -> Result<Json<Tag>, ApiError> {
+    let name = req.name.trim();
+
+    validation::validate_name("name", name).map_err(ApiError::InvalidFields)?;
+
+    let origin = client_origin(&headers, &auth.session_id);
+
+    let tag = data
+        .tags
+        .rename(&auth.user_id, origin.as_deref(), &id, name)
+        .await
+        .context("error renaming tag")?
+        .ok_or_else(|| ApiError::NotFound("tag not found".to_owned()))?;
+
+    Ok(Json(tag))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct VisitsRequest {
+    visits: Vec<VisitRequest>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct VisitRequest {
+    bookmark_id: String,
+    /// Clients that queue visits while offline send when they happened.
+    visited_at: Option<DateTime<Utc>>,
+}
+
+/// Records opens of the user's bookmarks, returns the visited bookmarks.
+/// Visits of unknown or deleted bookmarks are ignored.
+async fn visits_handler(
+    data: State<Data>,
+    Auth(auth): Auth,
+    headers: HeaderMap,
+    Json(req): Json<VisitsRequest>,
+) -> Result<Json<Vec<Bookmark>>, ApiError> {
+    let now = Utc::now();
+
+    // a client clock running ahead can't make visits count as recent forever
+    let visits = req
+        .visits
+        .into_iter()
+        .map(|visit| Visit {
+            bookmark_id: visit.bookmark_id,
+            visited_at: visit.visited_at.map_or(now, |at| at.min(now)),
+        })
+        .collect::<Vec<_>>();
+
+    let origin = client_origin(&headers, &auth.session_id);
 
-    Ok(())
+    let bookmarks = data
+        .bookmarks
+        .record_visits(&auth.user_id, origin.as_deref(), &visits)
+        .await
+        .context("error recording visits")?;
+
+    Ok(Json(bookmarks))
 }
 
 #[derive(Serialize, Deserialize)]
@@ -284,7 +638,7 @@ async fn register_handler(
     };
 
     data.users
-        .insert_with_session(&user, &session)
+        .insert_with_session(&user, session)
         .await
         .context("error inserting user with session")?;
 
@@ -316,6 +670,46 @@ async fn me_handler(
     })))
 }
 
+#[derive(Serialize, Deserialize)]
+struct UpdateMeRequest {
+    username: String,
+}
+
+async fn update_me_handler(
+    data: State<Data>,
+    UserId(user_id): UserId,
+    Json(req): Json<UpdateMeRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let updated = data
+        .users
+        .update_username(&user_id, &req.username)
+        .await
+        .context("error updating username")?;
+
+    if !updated {
+        return Err(ApiError::Conflict("username taken".to_owned()));
+    }
+
+    events::publish(
+        &data,
+        &Message {
+            user_id: user_id.to_owned(),
+            origin: None,
+            event: ServerEvent::AccountUpdated {
+                id: user_id.to_owned(),
+                username: req.username.to_owned(),
+            },
+        },
+    )
+    .await
+    .context("error publishing account updated")?;
+
+    Ok(Json(json!({
+        "id": user_id,
+        "username": req.username,
+    })))
+}
+
 async fn logout_handler(
     data: State<Data>,
     Auth(auth): Auth,
@@ -325,6 +719,19 @@ async fn logout_handler(
         .await
         .context("error deleting session")?;
 
+    events::publish(
+        &data,
+        &Message {
+            user_id: auth.user_id,
+            origin: None,
+            event: ServerEvent::SessionRevoked {
+                session_id: auth.session_id,
+            },
+        },
+    )
+    .await
+    .context("error publishing session revoked")?;
+
     Ok(AppendHeaders([(
         header::SET_COOKIE,
         create_empty_session_cookie()
@@ -332,3 +739,78 @@ async fn logout_handler(
             .context("error parsing cookie")?,
     )]))
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use serde_json::json;
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::testing::{bookmark, user};
+
+    async fn bootstrap(
+        data: &Data,
+        user_id: &str,
+        cursor: i64,
+    ) -> Result<Json<BootstrapResponse>, ApiError> {
+        bootstrap_handler(
+            State(data.clone()),
+            UserId(user_id.to_owned()),
+            Query(BootstrapRequest {
+                cursor: Some(cursor),
+                limit: None,
+            }),
+        )
+        .await
+    }
+
+    #[sqlx::test]
+    async fn bootstrap_behind_purged_changes_is_gone(pool: PgPool) {
+        let data = Data::from_pool(pool);
+        let user_id = user(&data).await;
+
+        let (kept, deleted) = (new_id(), new_id());
+
+        data.bookmarks
+            .bulk_upsert(
+                &user_id,
+                None,
+                &[
+                    bookmark(json!({ "id": kept })),
+                    bookmark(json!({ "id": deleted })),
+                ],
+            )
+            .await
+            .unwrap();
+        data.bookmarks
+            .bulk_upsert(
+                &user_id,
+                None,
+                &[bookmark(
+                    json!({ "id": deleted, "version": 2, "deleted_at": Utc::now() }),
+                )],
+            )
+            .await
+            .unwrap();
+
+        let purged = data
+            .bookmarks
+            .purge_tombstones(&(Utc::now() + Duration::minutes(1)))
+            .await
+            .unwrap();
+        assert_eq!(purged, 1);
+
+        // the client at seq 1 never saw the delete at seq 3
+        assert!(matches!(
+            bootstrap(&data, &user_id, 1).await,
+            Err(ApiError::Gone(_))
+        ));
+
+        // caught up clients and full resyncs go on as usual
+        assert!(bootstrap(&data, &user_id, 3).await.is_ok());
+
+        let changes = bootstrap(&data, &user_id, 0).await.unwrap().0.changes;
+        assert_eq!(changes.len(), 1);
+    }
+}
@@ -0,0 +1,71 @@
+use pulldown_cmark::{html, Options, Parser};
+
+/// Renders Markdown notes to HTML that's safe to embed in pages, anything
+/// that could run scripts or break out of the surrounding markup is removed.
+pub fn render(markdown: &str) -> String {
+    let parser = Parser::new_ext(
+        markdown,
+        Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TABLES,
+    );
+
+    let mut unsafe_html = String::new();
+    html::push_html(&mut unsafe_html, parser);
+
+    ammonia::clean(&unsafe_html)
+}
+
+/// Escapes text for use in HTML content and quoted attributes.
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_scripts() {
+        let html = render("hello <script>alert(1)</script>");
+
+        assert!(!html.contains("<script"));
+        assert!(!html.contains("alert(1)"));
+    }
+
+    #[test]
+    fn strips_javascript_links() {
+        for markdown in [
+            "[click](javascript:alert(1))",
+            "[click](JaVaScRiPt:alert(1))",
+            "<a href=\"javascript:alert(1)\">click</a>",
+        ] {
+            let html = render(markdown);
+
+            assert!(!html.to_lowercase().contains("javascript:"), "{html}");
+            assert!(html.contains("click"), "{html}");
+        }
+    }
+
+    #[test]
+    fn strips_raw_html() {
+        let html = render(
+            "<img src=x onerror=\"alert(1)\"><iframe src=\"https://example.com\"></iframe>\
+             <div style=\"position:fixed\">text</div>",
+        );
+
+        assert!(!html.contains("onerror"));
+        assert!(!html.contains("<iframe"));
+        assert!(!html.contains("style="));
+        assert!(html.contains("text"));
+    }
+
+    #[test]
+    fn keeps_markdown() {
+        let html = render("**bold** [link](https://example.com)");
+
+        assert!(html.contains("<strong>bold</strong>"));
+        assert!(html.contains("href=\"https://example.com\""));
+    }
+}
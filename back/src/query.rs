@@ -0,0 +1,282 @@
+use chrono::NaiveDate;
+
+/// A parsed search query, all terms have to match.
+///
+/// ```text
+/// query  = term*
+/// term   = "-"? (filter | phrase | word)
+/// filter = ("tag" | "site" | "before" | "after" | "is") ":" (phrase | word)
+/// phrase = '"' [^"]* '"'
+/// ```
+///
+/// Any other `key:value`, like `12:30` or `mailto:x`, is a word.
+#[derive(Debug, Clone, Default)]
+pub struct Query {
+    pub terms: Vec<Term>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Term {
+    pub negated: bool,
+    pub kind: TermKind,
+}
+
+#[derive(Debug, Clone)]
+pub enum TermKind {
+    Word(String),
+    Phrase(String),
+    Tag(String),
+    /// The host or any of its subdomains.
+    Site(String),
+    /// Saved before the start of the day.
+    Before(NaiveDate),
+    /// Saved on the day or later.
+    After(NaiveDate),
+    Deleted,
+}
+
+#[derive(Debug, Clone)]
+pub struct ParseError {
+    /// Character offset into the query.
+    pub position: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)
+    }
+}
+
+impl Query {
+    /// Whether deleted bookmarks are asked for, they're left out otherwise.
+    pub fn includes_deleted(&self) -> bool {
+        self.terms
+            .iter()
+            .any(|term| !term.negated && matches!(term.kind, TermKind::Deleted))
+    }
+}
+
+const FILTERS: [&str; 5] = ["tag", "site", "before", "after", "is"];
+
+pub fn parse(input: &str) -> Result<Query, ParseError> {
+    let chars = input.chars().collect::<Vec<_>>();
+    let mut pos = 0;
+    let mut terms = vec![];
+
+    loop {
+        while chars.get(pos).is_some_and(|c| c.is_whitespace()) {
+            pos += 1;
+        }
+
+        if pos >= chars.len() {
+            return Ok(Query { terms });
+        }
+
+        let negated = chars[pos] == '-';
+
+        if negated {
+            pos += 1;
+
+            if chars.get(pos).is_none_or(|c| c.is_whitespace()) {
+                return Err(error(pos, "expected a term after '-'"));
+            }
+        }
+
+        let kind = if chars[pos] == '"' {
+            TermKind::Phrase(parse_phrase(&chars, &mut pos)?)
+        } else {
+            parse_word_or_filter(&chars, &mut pos)?
+        };
+
+        terms.push(Term { negated, kind });
+    }
+}
+
+fn parse_word_or_filter(chars: &[char], pos: &mut usize) -> Result<TermKind, ParseError> {
+    let start = *pos;
+
+    while chars
+        .get(*pos)
+        .is_some_and(|c| !c.is_whitespace() && *c != ':')
+    {
+        *pos += 1;
+    }
+
+    let key = chars[start..*pos].iter().collect::<String>();
+
+    if chars.get(*pos) != Some(&':') {
+        return Ok(TermKind::Word(key));
+    }
+
+    // skip the colon
+    *pos += 1;
+    let value_start = *pos;
+
+    let value = if chars.get(*pos) == Some(&'"') {
+        parse_phrase(chars, pos)?
+    } else {
+        read_word(chars, pos)
+    };
+
+    // urls and unknown keys aren't filters
+    if value.starts_with("//") || !FILTERS.contains(&key.as_str()) {
+        return Ok(TermKind::Word(chars[start..*pos].iter().collect()));
+    }
+
+    if value.is_empty() {
+        return Err(error(
+            value_start,
+            format!("expected a value after '{key}:'"),
+        ));
+    }
+
+    let kind = match key.as_str() {
+        "tag" => TermKind::Tag(value),
+        "site" => TermKind::Site(value),
+        "before" => TermKind::Before(parse_date(&value, value_start)?),
+        "after" => TermKind::After(parse_date(&value, value_start)?),
+        "is" if value == "deleted" => TermKind::Deleted,
+        _ => return Err(error(value_start, format!("unknown value 'is:{value}'"))),
+    };
+
+    Ok(kind)
+}
+
+fn parse_phrase(chars: &[char], pos: &mut usize) -> Result<String, ParseError> {
+    let start = *pos;
+
+    // skip the opening quote
+    *pos += 1;
+
+    let Some(length) = chars[*pos..].iter().position(|c| *c == '"') else {
+        return Err(error(start, "unterminated quote"));
+    };
+
+    let phrase = chars[*pos..*pos + length].iter().collect();
+
+    *pos += length + 1;
+
+    Ok(phrase)
+}
+
+fn read_word(chars: &[char], pos: &mut usize) -> String {
+    let start = *pos;
+
+    while chars.get(*pos).is_some_and(|c| !c.is_whitespace()) {
+        *pos += 1;
+    }
+
+    chars[start..*pos].iter().collect()
+}
+
+fn parse_date(value: &str, position: usize) -> Result<NaiveDate, ParseError> {
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map_err(|_| error(position, "invalid date, expected YYYY-MM-DD"))
+}
+
+fn error(position: usize, message: impl Into<String>) -> ParseError {
+    ParseError {
+        position,
+        message: message.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(input: &str) -> Vec<(bool, String)> {
+        parse(input)
+            .unwrap()
+            .terms
+            .into_iter()
+            .map(|term| (term.negated, format!("{:?}", term.kind)))
+            .collect()
+    }
+
+    fn error_at(input: &str) -> (usize, String) {
+        let err = parse(input).unwrap_err();
+
+        (err.position, err.message)
+    }
+
+    #[test]
+    fn parses_words_and_phrases() {
+        assert_eq!(
+            kinds("  rust \"async book\"  "),
+            [
+                (false, r#"Word("rust")"#.to_owned()),
+                (false, r#"Phrase("async book")"#.to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_filters() {
+        assert_eq!(
+            kinds(r#"tag:"to read" site:example.com after:2024-01-31 is:deleted"#),
+            [
+                (false, r#"Tag("to read")"#.to_owned()),
+                (false, r#"Site("example.com")"#.to_owned()),
+                (false, "After(2024-01-31)".to_owned()),
+                (false, "Deleted".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_negation() {
+        assert_eq!(
+            kinds(r#"-tag:old -"exact phrase" -word"#),
+            [
+                (true, r#"Tag("old")"#.to_owned()),
+                (true, r#"Phrase("exact phrase")"#.to_owned()),
+                (true, r#"Word("word")"#.to_owned()),
+            ]
+        );
+        assert!(!parse("-is:deleted").unwrap().includes_deleted());
+    }
+
+    #[test]
+    fn keeps_colons_outside_filters_in_words() {
+        assert_eq!(
+            kinds("12:30 mailto:x https://example.com/a foo:"),
+            [
+                (false, r#"Word("12:30")"#.to_owned()),
+                (false, r#"Word("mailto:x")"#.to_owned()),
+                (false, r#"Word("https://example.com/a")"#.to_owned()),
+                (false, r#"Word("foo:")"#.to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_error_positions() {
+        assert_eq!(
+            error_at(r#"rust "async"#),
+            (5, "unterminated quote".to_owned())
+        );
+        assert_eq!(
+            error_at("rust - x"),
+            (6, "expected a term after '-'".to_owned())
+        );
+        assert_eq!(
+            error_at("tag:"),
+            (4, "expected a value after 'tag:'".to_owned())
+        );
+        assert_eq!(
+            error_at("a before:yesterday"),
+            (9, "invalid date, expected YYYY-MM-DD".to_owned())
+        );
+        assert_eq!(
+            error_at("is:starred"),
+            (3, "unknown value 'is:starred'".to_owned())
+        );
+    }
+
+    #[test]
+    fn counts_positions_in_characters() {
+        assert_eq!(error_at("héllo \"x"), (6, "unterminated quote".to_owned()));
+    }
+}
@@ -0,0 +1,42 @@
+use anyhow::Context;
+
+use crate::{
+    data::Data,
+    error::ApiError,
+    query,
+    search::{self, ListParams, SearchResponse},
+};
+
+/// Runs the user's saved search, paginated like full-text search.
+pub async fn evaluate(
+    data: &Data,
+    user_id: &str,
+    id: &str,
+    params: ListParams,
+) -> Result<SearchResponse, ApiError> {
+    let saved_search = data
+        .saved_searches
+        .get(user_id, id)
+        .await
+        .context("error getting saved search")?
+        .ok_or_else(|| ApiError::NotFound("saved search not found".to_owned()))?;
+
+    let query =
+        query::parse(&saved_search.query).map_err(|err| ApiError::BadRequest(err.to_string()))?;
+
+    let limit = search::page_limit(params.limit);
+
+    let after = params
+        .cursor
+        .as_deref()
+        .map(search::parse_cursor)
+        .transpose()?;
+
+    let hits = data
+        .bookmarks
+        .search(user_id, &query, params.sort, after, limit)
+        .await
+        .context("error searching bookmarks")?;
+
+    Ok(search::to_response(hits, limit))
+}
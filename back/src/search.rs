@@ -0,0 +1,161 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    data::{Bookmark, Data, SearchHit, SearchSort, MATCH_END, MATCH_START},
+    error::ApiError,
+    markdown::escape,
+    query::{self, Query},
+};
+
+const DEFAULT_LIMIT: i64 = 20;
+const MAX_LIMIT: i64 = 100;
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchMode {
+    /// Words in titles, urls and notes with field filters, see
+    /// [`crate::query::parse`].
+    #[default]
+    FullText,
+    /// Typo tolerant matching of titles and hosts.
+    Fuzzy,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SearchParams {
+    pub q: String,
+    #[serde(default)]
+    pub mode: SearchMode,
+    #[serde(default)]
+    pub sort: SearchSort,
+    /// `next_cursor` of the previous page.
+    pub cursor: Option<String>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListParams {
+    #[serde(default)]
+    pub sort: SearchSort,
+    /// `next_cursor` of the previous page.
+    pub cursor: Option<String>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SearchResponse {
+    pub results: Vec<SearchResult>,
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SearchResult {
+    pub bookmark: Bookmark,
+    /// Relevance, or frecency when sorted by it.
+    pub rank: f32,
+    pub frecency: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub similarity: Option<f32>,
+    /// Escaped HTML with matches in `<mark>` elements.
+    pub title_html: String,
+    pub notes_html: String,
+}
+
+pub async fn search(
+    data: &Data,
+    user_id: &str,
+    params: SearchParams,
+) -> Result<SearchResponse, ApiError> {
+    if params.q.trim().is_empty() {
+        return Err(ApiError::BadRequest("q must not be empty".to_owned()));
+    }
+
+    let limit = page_limit(params.limit);
+
+    let after = params.cursor.as_deref().map(parse_cursor).transpose()?;
+
+    let hits = match params.mode {
+        SearchMode::FullText => {
+            let query =
+                query::parse(&params.q).map_err(|err| ApiError::BadRequest(err.to_string()))?;
+
+            data.bookmarks
+                .search(user_id, &query, params.sort, after, limit)
+                .await
+        }
+        SearchMode::Fuzzy => {
+            data.bookmarks
+                .fuzzy_search(user_id, &params.q, params.sort, after, limit)
+                .await
+        }
+    }
+    .context("error searching bookmarks")?;
+
+    Ok(to_response(hits, limit))
+}
+
+/// Pages through the user's bookmarks that aren't deleted. Without a query
+/// they're all equally relevant, so sorting by relevance lists them by id.
+pub async fn list(
+    data: &Data,
+    user_id: &str,
+    params: ListParams,
+) -> Result<SearchResponse, ApiError> {
+    let limit = page_limit(params.limit);
+
+    let after = params.cursor.as_deref().map(parse_cursor).transpose()?;
+
+    let hits = data
+        .bookmarks
+        .search(user_id, &Query::default(), params.sort, after, limit)
+        .await
+        .context("error listing bookmarks")?;
+
+    Ok(to_response(hits, limit))
+}
+
+/// Page size from the requested limit.
+pub fn page_limit(limit: Option<i64>) -> i64 {
+    limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT)
+}
+
+/// Response for a page of hits, with a cursor to the next page when it's full.
+pub fn to_response(hits: Vec<SearchHit>, limit: i64) -> SearchResponse {
+    let next_cursor = if hits.len() == limit as usize {
+        hits.last()
+            .map(|hit| format!("{}:{}", hit.rank, hit.bookmark.id))
+    } else {
+        None
+    };
+
+    let results = hits
+        .into_iter()
+        .map(|hit| SearchResult {
+            title_html: highlight_html(&hit.title_highlight),
+            notes_html: highlight_html(&hit.notes_highlight),
+            bookmark: hit.bookmark,
+            rank: hit.rank,
+            frecency: hit.frecency,
+            similarity: hit.similarity,
+        })
+        .collect();
+
+    SearchResponse {
+        results,
+        next_cursor,
+    }
+}
+
+pub fn parse_cursor(cursor: &str) -> Result<(f32, &str), ApiError> {
+    cursor
+        .split_once(':')
+        .and_then(|(rank, id)| Some((rank.parse().ok()?, id)))
+        .ok_or_else(|| ApiError::BadRequest("invalid cursor".to_owned()))
+}
+
+fn highlight_html(highlight: &str) -> String {
+    escape(highlight)
+        .replace(MATCH_START, "<mark>")
+        .replace(MATCH_END, "</mark>")
+}
@@ -0,0 +1,628 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Context;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::error;
+
+use crate::{
+    config::CONFIG,
+    data::{Bookmark, Collection, CollectionWrite, Data, Reservation, SavedSearch},
+    error::ApiError,
+    url_policy,
+    validation::{
+        summary, validate_bookmark, validate_collection, validate_saved_search, FieldError,
+    },
+};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SyncRequest {
+    pub bookmarks: Vec<Bookmark>,
+    /// Applied before the bookmarks, so bookmarks can go into collections
+    /// created by the same request.
+    #[serde(default)]
+    pub collections: Vec<Collection>,
+    #[serde(default)]
+    pub saved_searches: Vec<SavedSearch>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SyncResponse {
+    pub results: Vec<SyncResult>,
+    #[serde(default)]
+    pub collection_results: Vec<CollectionSyncResult>,
+    #[serde(default)]
+    pub saved_search_results: Vec<SavedSearchSyncResult>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SyncResult {
+    pub id: String,
+    pub status: SyncStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// What's wrong with which field of an invalid bookmark.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldError>,
+    /// The server's copy after the sync, missing when the user has none.
+    pub bookmark: Option<Bookmark>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CollectionSyncResult {
+    pub id: String,
+    pub status: SyncStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldError>,
+    /// The server's copy after the sync, missing when the user has none.
+    pub collection: Option<Collection>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SavedSearchSyncResult {
+    pub id: String,
+    pub status: SyncStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldError>,
+    /// The server's copy after the sync, missing when the user has none.
+    pub saved_search: Option<SavedSearch>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncStatus {
+    Applied,
+    /// The bookmark changed on the server since the client last saw it.
+    Stale,
+    Invalid,
+    /// The id belongs to another user's bookmark.
+    Forbidden,
+}
+
+// matches the column
+const MAX_IDEMPOTENCY_KEY_LENGTH: usize = 255;
+
+// a reservation this old belongs to a request that died before finishing
+const PENDING_TIMEOUT_MINUTES: i64 = 5;
+
+// responses are stored as they are, bigger ones aren't kept at all
+const MAX_STORED_RESPONSE_BYTES: usize = 1024 * 1024;
+
+/// Applies the request once per `idempotency_key`, repeating a key within
+/// the idempotency window returns the stored response of the first call
+/// without writing anything, and fails while the first call is still running
+/// or when the key was used for a different request. Responses too big to
+/// store release the key, a replay applies the request again and the version
+/// checks turn its writes into stale results. The changes are announced as
+/// coming from `origin`.
+pub async fn sync(
+    data: &Data,
+    user_id: &str,
+    origin: Option<&str>,
+    idempotency_key: Option<&str>,
+    request: SyncRequest,
+) -> Result<SyncResponse, ApiError> {
+    let Some(key) = idempotency_key else {
+        return Ok(apply_all(data, user_id, origin, request).await?);
+    };
+
+    if key.chars().count() > MAX_IDEMPOTENCY_KEY_LENGTH {
+        return Err(ApiError::BadRequest(format!(
+            "idempotency key must be at most {MAX_IDEMPOTENCY_KEY_LENGTH} characters"
+        )));
+    }
+
+    let request_hash = serde_json::to_vec(&request)
+        .map(|bytes| hex::encode(Sha256::digest(bytes)))
+        .context("error hashing request")?;
+
+    let now = Utc::now();
+
+    let reservation = data
+        .idempotency_keys
+        .reserve(
+            user_id,
+            key,
+            &request_hash,
+            &(now - chrono::Duration::hours(CONFIG.idempotency_window_hours)),
+            &(now - chrono::Duration::minutes(PENDING_TIMEOUT_MINUTES)),
+        )
+        .await
+        .context("error reserving idempotency key")?;
+
+    match reservation {
+        Reservation::Reserved => {}
+        Reservation::Pending => {
+            return Err(ApiError::Conflict(
+                "a request with this idempotency key is still running".to_owned(),
+            ));
+        }
+        Reservation::Done(previous) => {
+            let response =
+                serde_json::from_value(previous).context("error parsing stored response")?;
+
+            return Ok(response);
+        }
+        Reservation::Mismatch => {
+            return Err(ApiError::UnprocessableEntity(
+                "idempotency key was already used for a different request".to_owned(),
+            ));
+        }
+    }
+
+    let response = match apply_all(data, user_id, origin, request).await {
+        Ok(response) => response,
+        Err(err) => {
+            if let Err(err) = data.idempotency_keys.release(user_id, key).await {
+                error!("error releasing idempotency key: {:#?}", err);
+            }
+
+            return Err(err.into());
+        }
+    };
+
+    let value = serde_json::to_value(&response).context("error serializing response")?;
+
+    if value.to_string().len() > MAX_STORED_RESPONSE_BYTES {
+        data.idempotency_keys
+            .release(user_id, key)
+            .await
+            .context("error releasing idempotency key")?;
+    } else {
+        data.idempotency_keys
+            .complete(user_id, key, &value)
+            .await
+            .context("error completing idempotency key")?;
+    }
+
+    Ok(response)
+}
+
+async fn apply_all(
+    data: &Data,
+    user_id: &str,
+    origin: Option<&str>,
+    request: SyncRequest,
+) -> anyhow::Result<SyncResponse> {
+    let saved_search_results =
+        apply_saved_searches(data, user_id, origin, request.saved_searches).await?;
+
+    let collection_results = apply_collections(data, user_id, origin, request.collections).await?;
+
+    let results = apply(data, user_id, origin, request.bookmarks).await?;
+
+    Ok(SyncResponse {
+        results,
+        collection_results,
+        saved_search_results,
+    })
+}
+
+async fn apply(
+    data: &Data,
+    user_id: &str,
+    origin: Option<&str>,
+    bookmarks: Vec<Bookmark>,
+) -> anyhow::Result<Vec<SyncResult>> {
+    let mut seen = HashSet::new();
+    let mut errors = HashMap::new();
+
+    let bookmarks = bookmarks
+        .into_iter()
+        .map(|mut b| {
+            b.tags = b.tags.map(normalize_tags);
+
+            // urls that don't normalize are reported by the validation below
+            if b.deleted_at.is_none() {
+                if let Ok(url) = url_policy::normalize(&b.url) {
+                    b.url = url;
+                }
+            }
+
+            b
+        })
+        .collect::<Vec<_>>();
+
+    let collection_ids = bookmarks
+        .iter()
+        .filter(|b| b.deleted_at.is_none())
+        .filter_map(|b| b.collection_id.to_owned().flatten())
+        .collect::<Vec<_>>();
+
+    let live_collections = data
+        .collections
+        .get_live_ids(user_id, &collection_ids)
+        .await
+        .context("error getting collections")?
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+    for (i, bookmark) in bookmarks.iter().enumerate() {
+        if !seen.insert(&bookmark.id) {
+            errors.insert(i, vec![FieldError::new("id", "duplicate id")]);
+        } else if let Err(errs) = validate_bookmark(bookmark) {
+            errors.insert(i, errs);
+        } else if bookmark.deleted_at.is_none()
+            && bookmark
+                .collection_id
+                .as_ref()
+                .and_then(Option::as_ref)
+                .is_some_and(|id| !live_collections.contains(id))
+        {
+            errors.insert(
+                i,
+                vec![FieldError::new("collection_id", "collection not found")],
+            );
+        }
+    }
+
+    let valid = bookmarks
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !errors.contains_key(i))
+        .map(|(_, b)| b.clone())
+        .collect::<Vec<_>>();
+
+    let written = data
+        .bookmarks
+        .bulk_upsert(user_id, origin, &valid)
+        .await
+        .context("error upserting bookmarks")?
+        .into_iter()
+        .map(|b| (b.id.to_owned(), b))
+        .collect::<HashMap<_, _>>();
+
+    let unwritten = bookmarks
+        .iter()
+        .filter(|b| !written.contains_key(&b.id))
+        .map(|b| b.id.to_owned())
+        .collect::<Vec<_>>();
+
+    // the user's own copies of everything that wasn't written, an unwritten id
+    // without one belongs to someone else
+    let existing = data
+        .bookmarks
+        .get_many(user_id, &unwritten)
+        .await
+        .context("error getting existing bookmarks")?
+        .into_iter()
+        .map(|b| (b.id.to_owned(), b))
+        .collect::<HashMap<_, _>>();
+
+    let results = bookmarks
+        .into_iter()
+        .enumerate()
+        .map(|(i, b)| {
+            let canonical = written.get(&b.id).or(existing.get(&b.id)).cloned();
+
+            let fields = errors.remove(&i).unwrap_or_default();
+
+            let status = if !fields.is_empty() {
+                SyncStatus::Invalid
+            } else if written.contains_key(&b.id) {
+                SyncStatus::Applied
+            } else if canonical.is_some() {
+                SyncStatus::Stale
+            } else {
+                SyncStatus::Forbidden
+            };
+
+            SyncResult {
+                id: b.id,
+                status,
+                error: (!fields.is_empty()).then(|| summary(&fields)),
+                fields,
+                bookmark: canonical,
+            }
+        })
+        .collect();
+
+    Ok(results)
+}
+
+async fn apply_collections(
+    data: &Data,
+    user_id: &str,
+    origin: Option<&str>,
+    collections: Vec<Collection>,
+) -> anyhow::Result<Vec<CollectionSyncResult>> {
+    let mut seen = HashSet::new();
+    let mut errors = HashMap::new();
+
+    for (i, collection) in collections.iter().enumerate() {
+        if !seen.insert(&collection.id) {
+            errors.insert(i, vec![FieldError::new("id", "duplicate id")]);
+        } else if let Err(errs) = validate_collection(collection) {
+            errors.insert(i, errs);
+        }
+    }
+
+    let valid = collections
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !errors.contains_key(i))
+        .map(|(_, c)| c.clone())
+        .collect::<Vec<_>>();
+
+    let mut writes = data
+        .collections
+        .bulk_upsert(user_id, origin, &valid)
+        .await
+        .context("error upserting collections")?
+        .into_iter();
+
+    let results = collections
+        .into_iter()
+        .enumerate()
+        .map(|(i, c)| {
+            let (status, fields, collection) = match errors.remove(&i) {
+                Some(errs) => (SyncStatus::Invalid, errs, None),
+                // one write per valid collection, in order
+                None => match writes.next() {
+                    Some(CollectionWrite::Written(c)) => (SyncStatus::Applied, vec![], Some(c)),
+                    Some(CollectionWrite::Stale(c)) => (SyncStatus::Stale, vec![], Some(c)),
+                    Some(CollectionWrite::Invalid(err)) => (
+                        SyncStatus::Invalid,
+                        vec![FieldError::new("parent_id", err)],
+                        None,
+                    ),
+                    Some(CollectionWrite::Forbidden) | None => {
+                        (SyncStatus::Forbidden, vec![], None)
+                    }
+                },
+            };
+
+            CollectionSyncResult {
+                id: c.id,
+                status,
+                error: (!fields.is_empty()).then(|| summary(&fields)),
+                fields,
+                collection,
+            }
+        })
+        .collect();
+
+    Ok(results)
+}
+
+async fn apply_saved_searches(
+    data: &Data,
+    user_id: &str,
+    origin: Option<&str>,
+    saved_searches: Vec<SavedSearch>,
+) -> anyhow::Result<Vec<SavedSearchSyncResult>> {
+    let mut seen = HashSet::new();
+    let mut errors = HashMap::new();
+
+    for (i, saved_search) in saved_searches.iter().enumerate() {
+        if !seen.insert(&saved_search.id) {
+            errors.insert(i, vec![FieldError::new("id", "duplicate id")]);
+        } else if let Err(errs) = validate_saved_search(saved_search) {
+            errors.insert(i, errs);
+        }
+    }
+
+    let valid = saved_searches
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !errors.contains_key(i))
+        .map(|(_, s)| s.clone())
+        .collect::<Vec<_>>();
+
+    let written = data
+        .saved_searches
+        .bulk_upsert(user_id, origin, &valid)
+        .await
+        .context("error upserting saved searches")?
+        .into_iter()
+        .map(|s| (s.id.to_owned(), s))
+        .collect::<HashMap<_, _>>();
+
+    let unwritten = saved_searches
+        .iter()
+        .filter(|s| !written.contains_key(&s.id))
+        .map(|s| s.id.to_owned())
+        .collect::<Vec<_>>();
+
+    let existing = data
+        .saved_searches
+        .get_many(user_id, &unwritten)
+        .await
+        .context("error getting existing saved searches")?
+        .into_iter()
+        .map(|s| (s.id.to_owned(), s))
+        .collect::<HashMap<_, _>>();
+
+    let results = saved_searches
+        .into_iter()
+        .enumerate()
+        .map(|(i, s)| {
+            let canonical = written.get(&s.id).or(existing.get(&s.id)).cloned();
+
+            let fields = errors.remove(&i).unwrap_or_default();
+
+            let status = if !fields.is_empty() {
+                SyncStatus::Invalid
+            } else if written.contains_key(&s.id) {
+                SyncStatus::Applied
+            } else if canonical.is_some() {
+                SyncStatus::Stale
+            } else {
+                SyncStatus::Forbidden
+            };
+
+            SavedSearchSyncResult {
+                id: s.id,
+                status,
+                error: (!fields.is_empty()).then(|| summary(&fields)),
+                fields,
+                saved_search: canonical,
+            }
+        })
+        .collect();
+
+    Ok(results)
+}
+
+/// Trims, dedupes and sorts tag names the way the server returns them.
+fn normalize_tags(tags: Vec<String>) -> Vec<String> {
+    let mut tags = tags
+        .into_iter()
+        .map(|tag| tag.trim().to_owned())
+        .filter(|tag| !tag.is_empty())
+        .collect::<Vec<_>>();
+
+    tags.sort();
+    tags.dedup();
+
+    tags
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::{
+        id::new_id,
+        testing::{bookmark, user},
+    };
+
+    async fn sync_bookmark(data: &Data, user_id: &str, bookmark: Bookmark) -> Bookmark {
+        let request = SyncRequest {
+            bookmarks: vec![bookmark],
+            collections: vec![],
+            saved_searches: vec![],
+        };
+
+        let mut response = sync(data, user_id, None, None, request).await.unwrap();
+        let result = response.results.pop().unwrap();
+
+        assert!(matches!(result.status, SyncStatus::Applied));
+
+        result.bookmark.unwrap()
+    }
+
+    #[sqlx::test]
+    async fn leaving_out_tags_keeps_them(pool: PgPool) {
+        let data = Data::from_pool(pool);
+        let user_id = user(&data).await;
+
+        let created = sync_bookmark(
+            &data,
+            &user_id,
+            bookmark(json!({ "tags": ["rust", "lang"] })),
+        )
+        .await;
+        assert_eq!(
+            created.tags,
+            Some(vec!["lang".to_owned(), "rust".to_owned()])
+        );
+
+        let edited = sync_bookmark(
+            &data,
+            &user_id,
+            bookmark(json!({ "id": created.id, "title": "edited", "version": created.version })),
+        )
+        .await;
+        assert_eq!(edited.tags, created.tags);
+
+        let stored = data
+            .bookmarks
+            .get_many(&user_id, &[created.id.to_owned()])
+            .await
+            .unwrap();
+        assert_eq!(stored[0].tags, created.tags);
+
+        // an empty list still clears them
+        let cleared = sync_bookmark(
+            &data,
+            &user_id,
+            bookmark(json!({ "id": created.id, "tags": [], "version": edited.version })),
+        )
+        .await;
+        assert_eq!(cleared.tags, Some(vec![]));
+    }
+
+    #[sqlx::test]
+    async fn leaving_out_collection_and_position_keeps_them(pool: PgPool) {
+        let data = Data::from_pool(pool);
+        let user_id = user(&data).await;
+
+        let collection: Collection = serde_json::from_value(json!({
+            "id": new_id(),
+            "name": "reading",
+            "updated_at": Utc::now(),
+            "deleted_at": null,
+        }))
+        .unwrap();
+        data.collections
+            .bulk_upsert(&user_id, None, std::slice::from_ref(&collection))
+            .await
+            .unwrap();
+
+        let created = sync_bookmark(
+            &data,
+            &user_id,
+            bookmark(json!({ "collection_id": collection.id, "position": 5 })),
+        )
+        .await;
+        assert_eq!(created.collection_id, Some(Some(collection.id.to_owned())));
+        assert_eq!(created.position, Some(5));
+
+        let edited = sync_bookmark(
+            &data,
+            &user_id,
+            bookmark(json!({ "id": created.id, "title": "edited", "version": created.version })),
+        )
+        .await;
+        assert_eq!(edited.collection_id, created.collection_id);
+        assert_eq!(edited.position, created.position);
+
+        // null still moves it to the root
+        let moved = sync_bookmark(
+            &data,
+            &user_id,
+            bookmark(json!({ "id": created.id, "collection_id": null, "version": edited.version })),
+        )
+        .await;
+        assert_eq!(moved.collection_id, Some(None));
+        assert_eq!(moved.position, created.position);
+    }
+
+    #[sqlx::test]
+    async fn leaving_out_notes_keeps_them(pool: PgPool) {
+        let data = Data::from_pool(pool);
+        let user_id = user(&data).await;
+
+        let created = sync_bookmark(
+            &data,
+            &user_id,
+            bookmark(json!({ "notes": "*read later*" })),
+        )
+        .await;
+
+        let edited = sync_bookmark(
+            &data,
+            &user_id,
+            bookmark(json!({ "id": created.id, "title": "edited", "version": created.version })),
+        )
+        .await;
+        assert_eq!(edited.notes.as_deref(), Some("*read later*"));
+
+        // an empty string still clears them
+        let cleared = sync_bookmark(
+            &data,
+            &user_id,
+            bookmark(json!({ "id": created.id, "notes": "", "version": edited.version })),
+        )
+        .await;
+        assert_eq!(cleared.notes.as_deref(), Some(""));
+    }
+}
@@ -0,0 +1,38 @@
+use chrono::Utc;
+use serde_json::{json, Value};
+
+use crate::{
+    data::{Bookmark, Data, User},
+    id::new_id,
+};
+
+/// Inserts a user to own the test's rows, returns their id.
+pub async fn user(data: &Data) -> String {
+    let user = User {
+        id: new_id(),
+        username: new_id(),
+        password_hash: String::new(),
+    };
+
+    data.users.insert(&user).await.unwrap();
+
+    user.id
+}
+
+/// A bookmark as a client would send it, `fields` override the defaults.
+pub fn bookmark(fields: Value) -> Bookmark {
+    let mut bookmark = json!({
+        "id": new_id(),
+        "title": "title",
+        "url": "https://example.com",
+        "updated_at": Utc::now(),
+        "deleted_at": null,
+        "version": 0,
+    });
+
+    for (key, value) in fields.as_object().unwrap() {
+        bookmark[key] = value.clone();
+    }
+
+    serde_json::from_value(bookmark).unwrap()
+}
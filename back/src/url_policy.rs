@@ -0,0 +1,222 @@
+use url::Url;
+
+use crate::config::CONFIG;
+
+const DEFAULT_SCHEMES: [&str; 2] = ["http", "https"];
+
+// these run code or read local data when opened, configuring them is ignored
+const FORBIDDEN_SCHEMES: [&str; 5] = ["javascript", "data", "vbscript", "blob", "file"];
+
+/// Parses the url and returns it in normal form, or why it can't be saved.
+/// Only http, https and the schemes in `EXTRA_URL_SCHEMES` are allowed.
+pub fn normalize(url: &str) -> Result<String, String> {
+    normalize_with(url, &CONFIG.extra_url_schemes)
+}
+
+fn normalize_with(url: &str, extra_schemes: &[String]) -> Result<String, String> {
+    let parsed = Url::parse(url.trim()).map_err(|err| format!("invalid url: {err}"))?;
+
+    if !is_allowed(parsed.scheme(), extra_schemes) {
+        return Err(format!("scheme {} is not allowed", parsed.scheme()));
+    }
+
+    Ok(parsed.to_string())
+}
+
+/// Whether the url has a scheme that isn't allowed, urls that don't parse
+/// can't be opened as anything but relative links so they aren't.
+pub fn is_unsafe(url: &str) -> bool {
+    is_unsafe_with(url, &CONFIG.extra_url_schemes)
+}
+
+fn is_unsafe_with(url: &str, extra_schemes: &[String]) -> bool {
+    Url::parse(url.trim()).is_ok_and(|parsed| !is_allowed(parsed.scheme(), extra_schemes))
+}
+
+fn is_allowed(scheme: &str, extra_schemes: &[String]) -> bool {
+    if FORBIDDEN_SCHEMES.contains(&scheme) {
+        return false;
+    }
+
+    DEFAULT_SCHEMES.contains(&scheme)
+        || extra_schemes
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(scheme))
+}
+
+/// The url in a form that's the same for urls pointing to the same page: no
+/// fragment, tracking params, `www.` or trailing slash, and http as https.
+/// Urls that don't parse are returned as they are.
+pub fn canonicalize(url: &str) -> String {
+    canonicalize_with(url, &CONFIG.tracking_params)
+}
+
+fn canonicalize_with(url: &str, tracking_params: &[String]) -> String {
+    let Ok(mut parsed) = Url::parse(url.trim()) else {
+        return url.to_owned();
+    };
+
+    parsed.set_fragment(None);
+
+    if parsed.scheme() == "http" {
+        // only fails for special to non-special scheme changes
+        let _ = parsed.set_scheme("https");
+    }
+
+    if let Some(host) = parsed.host_str().and_then(|host| host.strip_prefix("www.")) {
+        let host = host.to_owned();
+        let _ = parsed.set_host(Some(&host));
+    }
+
+    let params = parsed
+        .query_pairs()
+        .filter(|(name, _)| !is_tracking_param(name, tracking_params))
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect::<Vec<_>>();
+
+    if params.is_empty() {
+        parsed.set_query(None);
+    } else {
+        parsed.query_pairs_mut().clear().extend_pairs(params);
+    }
+
+    let path = parsed.path().to_owned();
+
+    if path.len() > 1 && path.ends_with('/') {
+        parsed.set_path(path.trim_end_matches('/'));
+    }
+
+    parsed.to_string()
+}
+
+fn is_tracking_param(name: &str, tracking_params: &[String]) -> bool {
+    let name = name.to_ascii_lowercase();
+
+    tracking_params.iter().any(|pattern| {
+        let pattern = pattern.to_ascii_lowercase();
+
+        match pattern.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == pattern,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn canonical(url: &str) -> String {
+        let tracking_params = ["utm_*", "fbclid"].map(str::to_owned);
+
+        canonicalize_with(url, &tracking_params)
+    }
+
+    #[test]
+    fn drops_fragment_www_and_trailing_slash() {
+        assert_eq!(
+            canonical("https://www.example.com/a/b/#top"),
+            "https://example.com/a/b"
+        );
+    }
+
+    #[test]
+    fn keeps_root_path() {
+        assert_eq!(canonical("https://example.com/"), "https://example.com/");
+    }
+
+    #[test]
+    fn upgrades_http() {
+        assert_eq!(canonical("http://example.com/a"), "https://example.com/a");
+    }
+
+    #[test]
+    fn drops_tracking_params() {
+        assert_eq!(
+            canonical("https://example.com/a?utm_source=x&id=1&FBCLID=y&UTM_medium=z"),
+            "https://example.com/a?id=1"
+        );
+        assert_eq!(
+            canonical("https://example.com/a?utm_source=x"),
+            "https://example.com/a"
+        );
+    }
+
+    #[test]
+    fn keeps_other_schemes() {
+        assert_eq!(canonical("ftp://www.example.com/a/"), "ftp://example.com/a");
+        assert_eq!(
+            canonical("mailto:someone@example.com"),
+            "mailto:someone@example.com"
+        );
+    }
+
+    #[test]
+    fn returns_unparsable_urls_as_they_are() {
+        assert_eq!(canonical("not a url"), "not a url");
+    }
+
+    fn normalized(url: &str) -> Result<String, String> {
+        let extra_schemes = ["ftp", "JavaScript", "DATA"].map(str::to_owned);
+
+        normalize_with(url, &extra_schemes)
+    }
+
+    #[test]
+    fn allows_http_and_https() {
+        assert_eq!(
+            normalized("https://example.com"),
+            Ok("https://example.com/".to_owned())
+        );
+        assert_eq!(
+            normalized("HTTP://example.com/a"),
+            Ok("http://example.com/a".to_owned())
+        );
+    }
+
+    #[test]
+    fn rejects_script_schemes() {
+        for url in [
+            "javascript:alert(1)",
+            "JaVaScRiPt:alert(1)",
+            "  javascript:alert(1)",
+            "\tjava\nscript:alert(1)",
+            "data:text/html,<script>alert(1)</script>",
+            "vbscript:msgbox(1)",
+            "blob:https://example.com/id",
+            "file:///etc/passwd",
+        ] {
+            assert!(normalized(url).is_err(), "{url}");
+            assert!(is_unsafe_with(url, &[]), "{url}");
+        }
+    }
+
+    #[test]
+    fn allows_configured_schemes() {
+        assert_eq!(
+            normalized("ftp://example.com/a"),
+            Ok("ftp://example.com/a".to_owned())
+        );
+        assert_eq!(
+            normalized("FTP://example.com/a"),
+            Ok("ftp://example.com/a".to_owned())
+        );
+        assert!(normalize_with("ftp://example.com/a", &[]).is_err());
+        assert!(normalized("mailto:someone@example.com").is_err());
+    }
+
+    #[test]
+    fn trims_whitespace() {
+        assert_eq!(
+            normalized("  https://example.com/a\n"),
+            Ok("https://example.com/a".to_owned())
+        );
+    }
+
+    #[test]
+    fn leaves_unparsable_urls_to_validation() {
+        assert!(normalized("example.com").is_err());
+        assert!(!is_unsafe_with("example.com", &[]));
+        assert!(!is_unsafe_with("https://example.com", &[]));
+    }
+}
@@ -0,0 +1,263 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    data::{Bookmark, Collection, SavedSearch},
+    query, url_policy,
+};
+
+const MAX_ID_LENGTH: usize = 30;
+const MAX_TITLE_LENGTH: usize = 2000;
+const MAX_URL_LENGTH: usize = 8192;
+const MAX_NOTES_LENGTH: usize = 100_000;
+const MAX_NAME_LENGTH: usize = 100;
+const MAX_QUERY_LENGTH: usize = 1000;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FieldError {
+    /// Name of the field in the request, `tags[2]` for list items.
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        FieldError {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
+
+/// Joins the messages into one line for places that only take a string.
+pub fn summary(errors: &[FieldError]) -> String {
+    errors
+        .iter()
+        .map(|err| format!("{}: {}", err.field, err.message))
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+pub fn validate_bookmark(bookmark: &Bookmark) -> Result<(), Vec<FieldError>> {
+    let mut errors = vec![];
+
+    check_id(&mut errors, "id", &bookmark.id);
+    check_text(&mut errors, "title", &bookmark.title, 0, MAX_TITLE_LENGTH);
+    if let Some(notes) = &bookmark.notes {
+        check_text(&mut errors, "notes", notes, 0, MAX_NOTES_LENGTH);
+    }
+
+    // deleting a bookmark saved before the url rules existed has to work
+    if bookmark.deleted_at.is_some() {
+        check_text(&mut errors, "url", &bookmark.url, 0, MAX_URL_LENGTH);
+    } else if let Err(message) = check_url(&bookmark.url) {
+        errors.push(FieldError::new("url", message));
+    }
+
+    if let Some(Some(collection_id)) = &bookmark.collection_id {
+        check_id(&mut errors, "collection_id", collection_id);
+    }
+
+    for (i, tag) in bookmark.tags.iter().flatten().enumerate() {
+        check_text(&mut errors, &format!("tags[{i}]"), tag, 1, MAX_NAME_LENGTH);
+    }
+
+    to_result(errors)
+}
+
+pub fn validate_collection(collection: &Collection) -> Result<(), Vec<FieldError>> {
+    let mut errors = vec![];
+
+    check_id(&mut errors, "id", &collection.id);
+    check_text(&mut errors, "name", &collection.name, 0, MAX_NAME_LENGTH);
+
+    if let Some(parent_id) = &collection.parent_id {
+        check_id(&mut errors, "parent_id", parent_id);
+    }
+
+    to_result(errors)
+}
+
+pub fn validate_saved_search(saved_search: &SavedSearch) -> Result<(), Vec<FieldError>> {
+    let mut errors = vec![];
+
+    check_id(&mut errors, "id", &saved_search.id);
+    check_text(&mut errors, "name", &saved_search.name, 0, MAX_NAME_LENGTH);
+    check_text(
+        &mut errors,
+        "query",
+        &saved_search.query,
+        0,
+        MAX_QUERY_LENGTH,
+    );
+
+    // same as urls, deleting a saved search has to work whatever its query
+    if saved_search.deleted_at.is_none() {
+        if let Err(err) = query::parse(&saved_search.query) {
+            errors.push(FieldError::new("query", err.to_string()));
+        }
+    }
+
+    to_result(errors)
+}
+
+/// Tag names.
+pub fn validate_name(field: &str, name: &str) -> Result<(), Vec<FieldError>> {
+    let mut errors = vec![];
+
+    check_text(&mut errors, field, name, 1, MAX_NAME_LENGTH);
+
+    to_result(errors)
+}
+
+fn to_result(errors: Vec<FieldError>) -> Result<(), Vec<FieldError>> {
+    if errors.is_empty() {
+        return Ok(());
+    }
+
+    Err(errors)
+}
+
+fn check_id(errors: &mut Vec<FieldError>, field: &str, id: &str) {
+    check_text(errors, field, id, 1, MAX_ID_LENGTH);
+}
+
+fn check_text(errors: &mut Vec<FieldError>, field: &str, text: &str, min: usize, max: usize) {
+    let length = text.chars().count();
+
+    if length < min || length > max {
+        let message = if min == 0 {
+            format!("must be at most {max} characters")
+        } else {
+            format!("must be {min}-{max} characters")
+        };
+
+        errors.push(FieldError::new(field, message));
+    }
+
+    // line breaks and tabs are fine in notes, the other fields are single line
+    let multiline = field == "notes";
+
+    if text
+        .chars()
+        .any(|c| c.is_control() && !(multiline && matches!(c, '\n' | '\r' | '\t')))
+    {
+        errors.push(FieldError::new(
+            field,
+            "must not contain control characters",
+        ));
+    }
+}
+
+fn check_url(url: &str) -> Result<(), String> {
+    if url.chars().count() > MAX_URL_LENGTH {
+        return Err(format!("must be at most {MAX_URL_LENGTH} characters"));
+    }
+
+    if url.chars().any(char::is_control) {
+        return Err("must not contain control characters".to_owned());
+    }
+
+    url_policy::normalize(url)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use serde_json::json;
+
+    use super::*;
+    use crate::testing::bookmark;
+
+    fn fields(result: Result<(), Vec<FieldError>>) -> Vec<String> {
+        result
+            .err()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|err| err.field)
+            .collect()
+    }
+
+    fn url(length: usize) -> String {
+        let prefix = "https://example.com/";
+
+        format!("{prefix}{}", "a".repeat(length - prefix.len()))
+    }
+
+    fn saved_search(name: &str, query: &str) -> SavedSearch {
+        serde_json::from_value(json!({
+            "id": "search",
+            "name": name,
+            "query": query,
+            "updated_at": Utc::now(),
+            "deleted_at": null,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn limits_title() {
+        let at_limit = bookmark(json!({ "title": "a".repeat(MAX_TITLE_LENGTH) }));
+        let over = bookmark(json!({ "title": "a".repeat(MAX_TITLE_LENGTH + 1) }));
+
+        assert!(validate_bookmark(&at_limit).is_ok());
+        assert_eq!(fields(validate_bookmark(&over)), ["title"]);
+    }
+
+    #[test]
+    fn limits_url() {
+        let at_limit = bookmark(json!({ "url": url(MAX_URL_LENGTH) }));
+        let over = bookmark(json!({ "url": url(MAX_URL_LENGTH + 1) }));
+
+        assert!(validate_bookmark(&at_limit).is_ok());
+        assert_eq!(fields(validate_bookmark(&over)), ["url"]);
+    }
+
+    #[test]
+    fn limits_notes() {
+        let at_limit = bookmark(json!({ "notes": "a".repeat(MAX_NOTES_LENGTH) }));
+        let over = bookmark(json!({ "notes": "a".repeat(MAX_NOTES_LENGTH + 1) }));
+
+        assert!(validate_bookmark(&at_limit).is_ok());
+        assert_eq!(fields(validate_bookmark(&over)), ["notes"]);
+    }
+
+    #[test]
+    fn limits_names() {
+        let at_limit = "a".repeat(MAX_NAME_LENGTH);
+        let over = "a".repeat(MAX_NAME_LENGTH + 1);
+
+        assert!(validate_name("name", &at_limit).is_ok());
+        assert_eq!(fields(validate_name("name", &over)), ["name"]);
+        assert!(validate_name("name", "").is_err());
+
+        let tagged = bookmark(json!({ "tags": [at_limit, over] }));
+        assert_eq!(fields(validate_bookmark(&tagged)), ["tags[1]"]);
+
+        assert!(validate_saved_search(&saved_search(&at_limit, "rust")).is_ok());
+        assert_eq!(
+            fields(validate_saved_search(&saved_search(&over, "rust"))),
+            ["name"]
+        );
+    }
+
+    #[test]
+    fn limits_query() {
+        let at_limit = "a".repeat(MAX_QUERY_LENGTH);
+        let over = "a".repeat(MAX_QUERY_LENGTH + 1);
+
+        assert!(validate_saved_search(&saved_search("search", &at_limit)).is_ok());
+        assert_eq!(
+            fields(validate_saved_search(&saved_search("search", &over))),
+            ["query"]
+        );
+    }
+
+    #[test]
+    fn counts_characters_not_bytes() {
+        let title = "é".repeat(MAX_TITLE_LENGTH);
+
+        assert!(validate_bookmark(&bookmark(json!({ "title": title }))).is_ok());
+    }
+}
@@ -0,0 +1,176 @@
+use std::{pin::pin, time::Duration};
+
+use axum::{
+    body::Bytes,
+    extract::ws::{Message, WebSocket},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::time::Instant;
+use tokio_stream::{Stream, StreamExt};
+
+use crate::{
+    data::Data,
+    events::{Envelope, ServerEvent},
+    sync::{self, SyncRequest, SyncResponse},
+};
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+// clients that haven't sent anything, pongs included, for this long are gone
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(45);
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    /// Same as `POST /sync`, with the `Idempotency-Key` header as a field so
+    /// resending a message after a reconnect doesn't apply it twice.
+    Sync {
+        id: String,
+        #[serde(default)]
+        idempotency_key: Option<String>,
+        #[serde(flatten)]
+        request: SyncRequest,
+    },
+    /// For clients that can't send websocket pings, like browsers.
+    Ping { id: String },
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage<'a> {
+    Ack {
+        id: String,
+        #[serde(flatten)]
+        response: SyncResponse,
+    },
+    Pong {
+        id: String,
+    },
+    Error {
+        id: Option<String>,
+        /// Has an `error` message, and `fields` for invalid fields.
+        #[serde(flatten)]
+        body: Value,
+    },
+    Event {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        seq: Option<i64>,
+        event: Envelope<'a>,
+    },
+}
+
+/// Carries both directions of the sync protocol over one socket: client
+/// messages are acked by id, and `events` are pushed as they come. The socket
+/// is closed once `events` ends, which happens when the session is revoked.
+pub async fn serve(
+    mut socket: WebSocket,
+    data: Data,
+    user_id: String,
+    origin: String,
+    events: impl Stream<Item = ServerEvent>,
+) {
+    let mut events = pin!(events);
+    let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+    let mut last_seen = Instant::now();
+
+    loop {
+        tokio::select! {
+            frame = socket.recv() => {
+                let Some(Ok(frame)) = frame else {
+                    break;
+                };
+
+                last_seen = Instant::now();
+
+                let reply = match frame {
+                    Message::Text(text) => {
+                        handle_message(&data, &user_id, &origin, text.as_str()).await
+                    }
+                    Message::Close(_) => break,
+                    // pings are answered by axum, pongs only count as activity
+                    _ => continue,
+                };
+
+                if send(&mut socket, &reply).await.is_err() {
+                    break;
+                }
+            }
+            event = events.next() => {
+                let Some(event) = event else {
+                    let _ = socket.send(Message::Close(None)).await;
+                    break;
+                };
+
+                let message = ServerMessage::Event {
+                    seq: event.seq(),
+                    event: event.envelope(),
+                };
+
+                if send(&mut socket, &message).await.is_err() {
+                    break;
+                }
+            }
+            _ = heartbeat.tick() => {
+                if last_seen.elapsed() > CLIENT_TIMEOUT {
+                    break;
+                }
+
+                if socket.send(Message::Ping(Bytes::new())).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+async fn handle_message<'a>(
+    data: &Data,
+    user_id: &str,
+    origin: &str,
+    text: &str,
+) -> ServerMessage<'a> {
+    let message = match serde_json::from_str::<ClientMessage>(text) {
+        Ok(message) => message,
+        Err(err) => {
+            return ServerMessage::Error {
+                id: None,
+                body: json!({ "error": format!("invalid message: {err}") }),
+            }
+        }
+    };
+
+    match message {
+        ClientMessage::Sync {
+            id,
+            idempotency_key,
+            request,
+        } => {
+            match sync::sync(
+                data,
+                user_id,
+                Some(origin),
+                idempotency_key.as_deref(),
+                request,
+            )
+            .await
+            {
+                Ok(response) => ServerMessage::Ack { id, response },
+                // same body as the http error
+                Err(err) => ServerMessage::Error {
+                    id: Some(id),
+                    body: err.into_status_and_body().1,
+                },
+            }
+        }
+        ClientMessage::Ping { id } => ServerMessage::Pong { id },
+    }
+}
+
+async fn send(socket: &mut WebSocket, message: &ServerMessage<'_>) -> anyhow::Result<()> {
+    let text = serde_json::to_string(message)?;
+
+    socket.send(Message::Text(text.into())).await?;
+
+    Ok(())
+}
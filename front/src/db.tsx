@@ -111,6 +111,7 @@ create table if not exists version (
 	updated_at text not null,
 	deleted_at text
 );`,
+		`alter table bookmarks add column version integer not null default 0;`,
 	];
 
 	const versionResult = await exec("select current from version limit 1");
@@ -165,6 +166,8 @@ export type Bookmark = {
 	title: string;
 	url: string;
 	updated_at: string;
+	// the server's version this copy is based on, 0 until it's first synced
+	version: number;
 };
 
 // UI Components for import/export
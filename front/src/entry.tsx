@@ -3,7 +3,7 @@ import { createStore } from "solid-js/store";
 import { registerSW } from "virtual:pwa-register";
 
 import { Auth } from "./auth";
-import { Bookmark, DbExport, DbImport, DbReset, DbSeed, db, deleteBookmark } from "./db";
+import { Bookmark, DbExport, DbImport, DbReset, DbSeed, db, deleteBookmark, id } from "./db";
 import { DeleteBookmark } from "./delete-bookmark";
 import { EditBookmark } from "./edit-bookmark";
 import { NewBookmark } from "./new-bookmark";
@@ -11,6 +11,8 @@ import { Settings } from "./settings";
 import { TheInput, normalizeUrl } from "./the-input";
 
 const BACK_URL = import.meta.env.VITE_BACK_URL;
+// identifies this tab, the server doesn't echo our own writes back to us
+const CLIENT_ID = id();
 let refetchBookmarks: (() => void) | null = null;
 
 const [showUpdate, setShowUpdate] = createSignal(false);
@@ -32,12 +34,13 @@ const updateSW = registerSW({
 	onOfflineReady() {},
 });
 
-type Message = {
+type ServerBookmark = {
 	id: string;
 	title: string;
 	url: string;
-	deleted_at: string;
+	deleted_at: string | null;
 	updated_at: string;
+	version: number;
 };
 function getSyncEnabled() {
 	return localStorage.getItem(SYNC_ENABLED) === "true";
@@ -46,44 +49,104 @@ function getSyncEnabled() {
 function subSse() {
 	if (!getSyncEnabled()) return;
 
-	const eventSource = new EventSource(BACK_URL + "/api/events", { withCredentials: true });
+	const params = new URLSearchParams({ client_id: CLIENT_ID });
+	const eventSource = new EventSource(BACK_URL + `/api/events?${params}`, {
+		withCredentials: true,
+	});
 
-	eventSource.onmessage = async (e) => {
+	// each event is named after its type, so `onmessage` never fires
+	const onBookmark = async (e: MessageEvent) => {
 		try {
-			const bookmark = JSON.parse(e.data) as Message;
-
-			await db.exec(
-				`
-insert into bookmarks (id, title, url, deleted_at, updated_at)
-values (?, ?, ?, ?, ?)
-on conflict(id)
-do update set
-	title = ?,
-	url = ?,
-	deleted_at = ?,
-	updated_at = ?;
-`,
-				[
-					bookmark.id,
-					bookmark.title,
-					bookmark.url,
-					bookmark.deleted_at,
-					bookmark.updated_at,
-					bookmark.title,
-					bookmark.url,
-					bookmark.deleted_at,
-					bookmark.updated_at,
-				]
-			);
+			const { data: bookmark } = JSON.parse(e.data) as { type: string; data: ServerBookmark };
+
+			await saveServerBookmarks([bookmark]);
 
 			refetchBookmarks?.();
 		} catch (error) {
 			console.error("invalid sse data", error);
 		}
 	};
+
+	eventSource.addEventListener("bookmark.upserted", onBookmark);
+	eventSource.addEventListener("bookmark.deleted", onBookmark);
+
+	// every session of the user hears about a revoke, only stop when it was ours
+	eventSource.addEventListener("session.revoked", async () => {
+		const res = await fetch(BACK_URL + "/api/me", { credentials: "include" });
+		if (res.ok) return;
+
+		eventSource.close();
+		refetchUser();
+	});
+
+	// events were dropped, catch up through bootstrap
+	eventSource.addEventListener("resync.required", async () => {
+		try {
+			await init();
+			refetchBookmarks?.();
+		} catch (error) {
+			console.error("resync failed", error);
+		}
+	});
+}
+
+// the server's copies win, they carry the versions the next edits are based on
+async function saveServerBookmarks(bookmarks: Array<ServerBookmark>) {
+	if (bookmarks.length === 0) return;
+
+	const values = [];
+	const params = [];
+
+	for (const bookmark of bookmarks) {
+		values.push("(?, ?, ?, ?, ?, ?)");
+		params.push(
+			bookmark.id,
+			bookmark.title,
+			bookmark.url,
+			bookmark.deleted_at,
+			bookmark.updated_at,
+			bookmark.version
+		);
+	}
+
+	await db.exec(
+		`INSERT INTO bookmarks (id, title, url, deleted_at, updated_at, version)
+			 VALUES ${values.join(",")}
+			 ON CONFLICT(id) DO UPDATE SET
+			   title = excluded.title,
+			   url = excluded.url,
+			   deleted_at = excluded.deleted_at,
+			   updated_at = excluded.updated_at,
+			   version = excluded.version`,
+		params
+	);
+}
+
+async function pushChanges(localChanges: Array<Bookmark>) {
+	const response = await fetch(BACK_URL + "/api/sync", {
+		method: "POST",
+		body: JSON.stringify({
+			bookmarks: localChanges,
+		}),
+		headers: { "Content-Type": "application/json", "X-Client-Id": CLIENT_ID },
+		credentials: "include",
+	});
+
+	if (!response.ok) throw new Error("Sync failed");
+
+	const { results } = await response.json();
+
+	// applied and stale results both come with the server's current copy
+	await saveServerBookmarks(
+		results.flatMap((result: { bookmark: ServerBookmark | null }) =>
+			result.bookmark ? [result.bookmark] : []
+		)
+	);
 }
 
 const LAST_SYNC = "last_sync";
+// seq of the last change received through bootstrap, the next one resumes after it
+const LAST_VERSION = "last_version";
 const SYNC_ENABLED = "sync_enabled";
 const CHUNK_SIZE = 500;
 
@@ -96,47 +159,45 @@ async function init() {
 	const localCount = await db.query("SELECT COUNT(*) as count FROM bookmarks limit 1;");
 	const wasEmpty = localCount[0].count === 0;
 
-	let serverCursor = null;
+	let serverCursor = localStorage.getItem(LAST_VERSION);
 	while (true) {
-		const response = await fetch(
-			BACK_URL +
-				`/api/bootstrap?cursor=${serverCursor || ""}&limit=${CHUNK_SIZE}&from=${lastSyncedAt}`,
-			{ credentials: "include" }
-		);
+		const params = new URLSearchParams({ limit: String(CHUNK_SIZE) });
+		if (serverCursor) params.set("cursor", serverCursor);
+
+		const response = await fetch(BACK_URL + `/api/bootstrap?${params}`, {
+			credentials: "include",
+		});
+
+		// the changes after our cursor were purged, start over from the beginning.
+		// synced copies go too, as deletes among the purged changes never reach
+		// us, edits that weren't pushed yet are kept and pushed below
+		if (response.status === 410 && serverCursor) {
+			await db.exec("DELETE FROM bookmarks WHERE version > 0 AND updated_at <= ?", [
+				lastSyncedAt,
+			]);
+			localStorage.removeItem(LAST_VERSION);
+			serverCursor = null;
+			continue;
+		}
 
 		if (!response.ok) throw new Error("Bootstrap failed");
 
-		const { bookmarks, next_cursor } = await response.json();
-
-		if (bookmarks.length > 0) {
-			const values = [];
-			const params = [];
-
-			for (const bookmark of bookmarks) {
-				values.push("(?, ?, ?, ?, ?)");
-				params.push(
-					bookmark.id,
-					bookmark.title,
-					bookmark.url,
-					bookmark.deleted_at,
-					bookmark.updated_at
-				);
-			}
-
-			await db.exec(
-				`INSERT INTO bookmarks (id, title, url, deleted_at, updated_at)
-					 VALUES ${values.join(",")}
-					 ON CONFLICT(id) DO UPDATE SET
-					   title = excluded.title,
-					   url = excluded.url,
-					   deleted_at = excluded.deleted_at,
-					   updated_at = excluded.updated_at`,
-				params
-			);
+		const { changes, next_cursor } = (await response.json()) as {
+			changes: Array<{ seq: number; bookmark?: ServerBookmark }>;
+			next_cursor: number | null;
+		};
+
+		// only bookmarks are stored locally so far
+		await saveServerBookmarks(
+			changes.flatMap((change) => (change.bookmark ? [change.bookmark] : []))
+		);
+
+		if (changes.length > 0) {
+			localStorage.setItem(LAST_VERSION, String(changes[changes.length - 1].seq));
 		}
 
 		if (!next_cursor) break;
-		serverCursor = next_cursor;
+		serverCursor = String(next_cursor);
 	}
 
 	if (wasEmpty) return;
@@ -145,7 +206,7 @@ async function init() {
 	let clientCursor = null;
 
 	while (hasMore) {
-		const localChanges = await db.query(
+		const localChanges = (await db.query(
 			`SELECT * FROM bookmarks 
 					 WHERE updated_at > ? 
 					 AND updated_at <= ?
@@ -153,7 +214,7 @@ async function init() {
 					 ORDER BY updated_at, id
 					 LIMIT ?`,
 			[lastSyncedAt, syncStartedAt, clientCursor, clientCursor, CHUNK_SIZE]
-		);
+		)) as Array<Bookmark>;
 
 		hasMore = localChanges.length === CHUNK_SIZE;
 		if (hasMore) {
@@ -161,16 +222,7 @@ async function init() {
 		}
 
 		if (localChanges.length > 0) {
-			const response = await fetch(BACK_URL + "/api/sync", {
-				method: "POST",
-				body: JSON.stringify({
-					bookmarks: localChanges,
-				}),
-				headers: { "Content-Type": "application/json" },
-				credentials: "include",
-			});
-
-			if (!response.ok) throw new Error("Sync failed");
+			await pushChanges(localChanges);
 		}
 	}
 	localStorage.setItem(LAST_SYNC, syncStartedAt);
@@ -185,14 +237,14 @@ async function sync() {
 	let clientCursor = null;
 
 	while (hasMore) {
-		const localChanges = await db.query(
+		const localChanges = (await db.query(
 			`SELECT * FROM bookmarks 
 					 WHERE updated_at > ? 
 					 AND (? IS NULL OR id > ?)
 					 ORDER BY updated_at, id
 					 LIMIT ?`,
 			[lastSyncedAt, clientCursor, clientCursor, CHUNK_SIZE]
-		);
+		)) as Array<Bookmark>;
 
 		hasMore = localChanges.length === CHUNK_SIZE;
 		if (hasMore) {
@@ -200,16 +252,7 @@ async function sync() {
 		}
 
 		if (localChanges.length > 0) {
-			const response = await fetch(BACK_URL + "/api/sync", {
-				method: "POST",
-				body: JSON.stringify({
-					bookmarks: localChanges,
-				}),
-				headers: { "Content-Type": "application/json" },
-				credentials: "include",
-			});
-
-			if (!response.ok) throw new Error("Sync failed");
+			await pushChanges(localChanges);
 		}
 	}
 	localStorage.setItem(LAST_SYNC, new Date().toISOString());